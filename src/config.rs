@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+
+use art_of_rally_leaderboard_api::{Area, Direction, Group, Platform, Weather};
+use itertools::Itertools as _;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt as _, Whatever, whatever};
+
+use crate::table_utils::Precision;
+use crate::{DriverOfTheRallyWeights, Rally, TieBreak, get_default_rallys};
+
+#[derive(Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+}
+
+/// A rally as written in the config, see [`crate::Rally::from_config`].
+#[derive(Deserialize)]
+pub struct RallyConfig {
+    pub title: String,
+    pub area: Area,
+    pub group: Group,
+    pub weather: Weather,
+    #[serde(default = "forward")]
+    pub direction: Direction,
+    /// Track the stages in each of these directions, instead of `direction`.
+    #[serde(default)]
+    pub directions: Vec<Direction>,
+    /// Track the stages in each of these weathers, instead of `weather`.
+    #[serde(default)]
+    pub weathers: Vec<Weather>,
+    pub stages: Vec<usize>,
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// How the tables on the stage pages are sorted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StageSort {
+    /// Fastest first.
+    #[default]
+    Time,
+    /// Best world rank first, with drivers without one last.
+    WorldRank,
+}
+
+/// How much detail to post in the notification.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationStyle {
+    /// Every changed time on every stage.
+    #[default]
+    Detailed,
+    /// One line per driver and rally with their net improvement.
+    Compact,
+}
+
+fn forward() -> Direction {
+    Direction::Forward
+}
+
+fn default_pace_buckets() -> Vec<f32> {
+    vec![101.0, 105.0]
+}
+
+fn one() -> usize {
+    1
+}
+
+fn default_font_stylesheets() -> Vec<String> {
+    vec![
+        "https://fonts.googleapis.com/css2?family=Atkinson+Hyperlegible+Next:ital,wght@0,200..800;1,200..800&display=swap".to_string(),
+        "https://fonts.googleapis.com/css2?family=Ubuntu+Mono:ital,wght@0,400;0,700;1,400;1,700&display=swap".to_string(),
+    ]
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub platform: Platform,
+    pub webhook_url: String,
+    pub users: Vec<User>,
+    /// A CSV or TSV file of `id,name` lines with more users, see
+    /// [`Config::load_users_file`].
+    #[serde(default)]
+    pub users_file: Option<String>,
+    /// How to order drivers with the same total time.
+    #[serde(default)]
+    pub tie_break: TieBreak,
+    /// Show a banner with the latest changes to returning visitors.
+    #[serde(default)]
+    pub whats_new_banner: bool,
+    /// Weights for crowning the driver of the rally.
+    #[serde(default)]
+    pub driver_of_the_rally: DriverOfTheRallyWeights,
+    /// The rallys to track, instead of [`get_default_rallys`].
+    #[serde(default, rename = "rally")]
+    pub rallys: Option<Vec<RallyConfig>>,
+    /// Track the reverse stages of the default rallys too.
+    #[serde(default)]
+    pub include_reverse_stages: bool,
+    /// The weathers to track the default rallys in, only dry if empty.
+    #[serde(default)]
+    pub default_weathers: Vec<Weather>,
+    /// Show each driver's improvement since the last run on the stage pages.
+    #[serde(default)]
+    pub improvement_column: bool,
+    /// Show when each time was set under it in the rally and stage tables.
+    #[serde(default)]
+    pub show_set_dates: bool,
+    /// Point out the driver with the most last places under each rally.
+    #[serde(default)]
+    pub bogey_driver: bool,
+    /// Stylesheets linked from every page, for fonts. Empty to use the
+    /// browser's fonts.
+    #[serde(default = "default_font_stylesheets")]
+    pub font_stylesheets: Vec<String>,
+    /// Pairs of drivers to notify about whenever they swap places.
+    #[serde(default)]
+    pub rivalries: Vec<[String; 2]>,
+    #[serde(default)]
+    pub notification_style: NotificationStyle,
+    /// Webhooks to notify about a rally instead of `webhook_url`, by rally
+    /// title.
+    #[serde(default)]
+    pub rally_webhooks: BTreeMap<String, Vec<String>>,
+    /// Milliseconds per unit of the API's `score`. It's reported in
+    /// milliseconds, so this only needs changing if the API does.
+    #[serde(default = "one")]
+    pub ms_per_score: usize,
+    /// Show this many of the fastest times in the world on the stage pages.
+    #[serde(default)]
+    pub world_top: Option<usize>,
+    /// Move drivers who haven't set a new time in this many runs from the
+    /// tables to the alumni page.
+    #[serde(default)]
+    pub hide_inactive_after: Option<usize>,
+    /// Don't notify about improvements smaller than this many milliseconds,
+    /// unless the rank changed too.
+    #[serde(default)]
+    pub notify_threshold_ms: usize,
+    /// Discord user ids by driver name, to mention drivers in notifications
+    /// when they improve a time.
+    #[serde(default)]
+    pub discord_ids: BTreeMap<String, u64>,
+    /// A driver to highlight in every table.
+    #[serde(default)]
+    pub focus_driver: Option<String>,
+    /// Show the focus driver first in the rally tables, whatever their rank.
+    #[serde(default)]
+    pub pin_focus_driver: bool,
+    #[serde(default)]
+    pub stage_sort: StageSort,
+    /// Put every weather and direction of a stage on one page, instead of a
+    /// page each.
+    #[serde(default)]
+    pub combine_stage_variants: bool,
+    /// Old names of renamed drivers, by old name, with their current name.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Upper bounds of the percentage of the fastest time, in increasing
+    /// order, for coloring times in the combined table. Times get the class
+    /// `pace-0` up to the first bound, `pace-1` up to the second and so on.
+    #[serde(default = "default_pace_buckets")]
+    pub pace_buckets: Vec<f32>,
+}
+
+impl Config {
+    /// Whether `name` is the [`Config::focus_driver`].
+    pub fn is_focus(&self, name: &str) -> bool {
+        self.focus_driver.as_deref() == Some(name)
+    }
+
+    /// User ids and user names, in the same order.
+    pub fn users(&self) -> (Vec<u64>, Vec<&str>) {
+        self.users
+            .iter()
+            .map(|user| (user.id, user.name.as_str()))
+            .unzip()
+    }
+
+    /// The configured rallys, or the default ones if there are none.
+    pub fn rallys(&self) -> Result<Vec<Rally>, Whatever> {
+        let Some(rallys) = &self.rallys else {
+            return Ok(get_default_rallys(
+                self.include_reverse_stages,
+                &self.default_weathers,
+            ));
+        };
+        // Results are matched between runs by title
+        if let Some(title) = rallys.iter().map(|rally| &rally.title).duplicates().next() {
+            whatever!("Rally title {title:?} is configured more than once");
+        }
+        rallys.iter().map(Rally::from_config).collect()
+    }
+
+    /// Add the users from `users_file`, skipping ids that are already
+    /// configured inline.
+    pub fn load_users_file(&mut self) -> Result<(), Whatever> {
+        let Some(path) = &self.users_file else {
+            return Ok(());
+        };
+        let s = std::fs::read_to_string(path)
+            .with_whatever_context(|e| format!("Couldn't read users file {path}\n{e}"))?;
+        for (line_idx, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((id, name)) = line.split_once([',', '\t']) else {
+                whatever!("{path}:{}: expected `id,name`", line_idx + 1);
+            };
+            let id = id.trim().parse().with_whatever_context(|e| {
+                format!(
+                    "{path}:{}: couldn't parse user id {id:?}\n{e}",
+                    line_idx + 1
+                )
+            })?;
+            if self.users.iter().any(|user| user.id == id) {
+                continue;
+            }
+            self.users.push(User {
+                id,
+                name: name.trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that the config makes sense before we start downloading things.
+    pub fn validate(&self) -> Result<(), Whatever> {
+        if self.users.is_empty() {
+            whatever!("No users configured");
+        }
+        if let Some(id) = self.users.iter().map(|user| user.id).duplicates().next() {
+            whatever!("User id {id} is configured more than once");
+        }
+        if let Some(name) = self
+            .users
+            .iter()
+            .map(|user| user.name.as_str())
+            .duplicates()
+            .next()
+        {
+            whatever!("User name {name:?} is configured more than once");
+        }
+        for webhook_url in
+            std::iter::once(&self.webhook_url).chain(self.rally_webhooks.values().flatten())
+        {
+            if !webhook_url.starts_with("https://") {
+                whatever!("Webhook URL {webhook_url:?} doesn't look like a URL");
+            }
+        }
+        if !self.pace_buckets.is_sorted() {
+            whatever!(
+                "pace_buckets {:?} should be in increasing order",
+                self.pace_buckets
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PlatformName;
+
+    #[test]
+    fn users_file_adds_users() {
+        let path = std::env::temp_dir().join(format!("aor-utils-users-{}.csv", std::process::id()));
+        std::fs::write(&path, "# id,name\n1,alice\n2\tbob\n\n3, carol\n").unwrap();
+        let platform = "steam".parse::<PlatformName>().unwrap().0;
+        let mut config: Config = toml::from_str(&format!(
+            "platform = {}\nwebhook_url = \"https://example.com\"\nusers_file = {:?}\n\n[[users]]\nid = 2\nname = \"robert\"\n",
+            serde_json::to_string(&platform).unwrap(),
+            path.display().to_string(),
+        ))
+        .unwrap();
+        let loaded = config.load_users_file();
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        // The inline user wins over the file
+        let users = config
+            .users
+            .iter()
+            .map(|user| (user.id, user.name.as_str()))
+            .collect_vec();
+        assert_eq!(users, [(2, "robert"), (1, "alice"), (3, "carol")]);
+    }
+
+    #[test]
+    fn users_file_errors_have_line_numbers() {
+        let path =
+            std::env::temp_dir().join(format!("aor-utils-bad-users-{}.csv", std::process::id()));
+        std::fs::write(&path, "1,alice\nbob\n").unwrap();
+        let platform = "steam".parse::<PlatformName>().unwrap().0;
+        let mut config: Config = toml::from_str(&format!(
+            "platform = {}\nwebhook_url = \"https://example.com\"\nusers = []\nusers_file = {:?}\n",
+            serde_json::to_string(&platform).unwrap(),
+            path.display().to_string(),
+        ))
+        .unwrap();
+        let e = config.load_users_file().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(e.to_string().ends_with(":2: expected `id,name`"), "{e}");
+    }
+}
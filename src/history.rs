@@ -0,0 +1,621 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use art_of_rally_leaderboard_api::Platform;
+use itertools::Itertools as _;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt as _, Whatever, whatever};
+
+use crate::{Rally, RallyResults, StageResult, StageWithLeaderboard, stage_key};
+
+/// Bumped whenever the snapshot format changes. Older snapshots are migrated
+/// when they're read, see [`read_db`].
+///
+/// 1: Added `StageResult::observed_at`.
+/// 2: Added `StageResult::world_entries`.
+pub const DB_VERSION: u32 = 2;
+
+/// A snapshot of all results, saved as `data/{ts}.ron` on every run.
+#[derive(Deserialize, Serialize)]
+pub struct Db {
+    #[serde(default)]
+    pub version: u32,
+    pub rallys: Vec<Rally>,
+    pub results: Vec<RallyResults>,
+    pub platform: Platform,
+    pub user_ids: Vec<u64>,
+    pub user_names: Vec<String>,
+}
+
+impl Db {
+    /// The results for the rally with the given title.
+    pub fn rally_results(&self, title: &str) -> Option<&RallyResults> {
+        self.rallys
+            .iter()
+            .zip(self.results.iter())
+            .find_map(|(rally, results)| rally.title.eq(title).then_some(results))
+    }
+
+    /// When the newest time in the snapshot was first seen, if any of its
+    /// times say.
+    pub fn updated_at(&self) -> Option<i64> {
+        self.results
+            .iter()
+            .flat_map(|results| &results.driver_results)
+            .flat_map(|driver| driver.stages.iter().flatten())
+            .filter_map(|stage_result| stage_result.observed_at)
+            .max()
+    }
+
+    /// Rename drivers by `aliases`, from old name to current name, so the
+    /// results of renamed drivers line up with their new name. If a driver
+    /// has results under both names, the faster time on each stage is kept.
+    pub fn apply_aliases(&mut self, aliases: &BTreeMap<String, String>) {
+        if aliases.is_empty() {
+            return;
+        }
+        let alias = |name: String| aliases.get(&name).cloned().unwrap_or(name);
+        self.user_names = std::mem::take(&mut self.user_names)
+            .into_iter()
+            .map(alias)
+            .collect();
+        for results in &mut self.results {
+            let stages = results.stages.clone();
+            let mut driver_results: BTreeMap<String, Vec<Option<StageResult>>> = BTreeMap::new();
+            let mut merged = false;
+            for driver in std::mem::take(&mut results.driver_results) {
+                match driver_results.entry(alias(driver.name)) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(driver.stages);
+                    }
+                    Entry::Occupied(mut entry) => {
+                        merge_fastest(entry.get_mut(), &driver.stages);
+                        merged = true;
+                    }
+                }
+            }
+            // Ranks are only off if someone is in there twice
+            if merged {
+                rank_locally(&mut driver_results, stages.len());
+            }
+            *results = RallyResults::from_driver_results(stages, driver_results);
+        }
+    }
+
+    /// Bring a snapshot up to [`DB_VERSION`]. Snapshots from newer versions
+    /// can't be read, since we don't know what changed.
+    fn migrate(mut self) -> Result<Db, Whatever> {
+        if self.version > DB_VERSION {
+            whatever!(
+                "Snapshot is version {}, but only versions up to {DB_VERSION} are supported",
+                self.version
+            );
+        }
+        while self.version < DB_VERSION {
+            match self.version {
+                // New fields default to `None`, which is what they mean for old
+                // snapshots, so there's nothing to do.
+                0 | 1 => {}
+                _ => unreachable!(),
+            }
+            self.version += 1;
+        }
+        Ok(self)
+    }
+}
+
+/// Read a snapshot, migrating it to [`DB_VERSION`] if it's older.
+pub fn read_db(path: impl AsRef<Path>) -> Result<Db, Whatever> {
+    let path = path.as_ref();
+    let s = std::fs::read_to_string(path)
+        .with_whatever_context(|e| format!("Couldn't read {}\n{e}", path.display()))?;
+    let db: Db = ron::from_str(&s)
+        .with_whatever_context(|e| format!("Couldn't parse {}\n{e}", path.display()))?;
+    db.migrate()
+        .with_whatever_context(|e| format!("Couldn't read {}\n{e}", path.display()))
+}
+
+/// The paths of all snapshots in `dir` with their timestamps, oldest first.
+///
+/// Files that aren't named `{ts}.ron` are skipped, which includes half-written
+/// snapshots. Timestamps are sorted as numbers, so `999999999.ron` comes
+/// before `1000000000.ron`.
+fn snapshot_paths(dir: &Path) -> Result<Vec<(i64, PathBuf)>, Whatever> {
+    Ok(std::fs::read_dir(dir)
+        .with_whatever_context(|e| format!("Couldn't read {}\n{e}", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+        .filter_map(|path| {
+            let ts = path.file_stem()?.to_str()?.parse::<i64>().ok()?;
+            Some((ts, path))
+        })
+        .sorted_by_key(|(ts, _)| *ts)
+        .collect())
+}
+
+/// All snapshots in `dir` with their timestamps, oldest first, see
+/// [`snapshot_paths`].
+pub fn load_snapshots(dir: impl AsRef<Path>) -> Result<Vec<(i64, Db)>, Whatever> {
+    snapshot_paths(dir.as_ref())?
+        .into_iter()
+        .map(|(ts, path)| Ok((ts, read_db(path)?)))
+        .collect()
+}
+
+/// The newest snapshot in `dir`, if there is one.
+pub fn latest_snapshot(dir: impl AsRef<Path>) -> Result<Option<Db>, Whatever> {
+    Ok(latest_snapshot_at(dir)?.map(|(_, db)| db))
+}
+
+/// Like [`latest_snapshot`], with its timestamp.
+pub fn latest_snapshot_at(dir: impl AsRef<Path>) -> Result<Option<(i64, Db)>, Whatever> {
+    snapshot_paths(dir.as_ref())?
+        .pop()
+        .map(|(ts, path)| Ok((ts, read_db(path)?)))
+        .transpose()
+}
+
+/// A rally's results with each driver's fastest time on each stage from any
+/// of the snapshots, so a driver who finished different stages in different
+/// runs gets all of them. Local ranks are recomputed from the merged times.
+///
+/// The stages are taken from the last snapshot that has the rally, and matched
+/// up with the stages of older snapshots by [`stage_key`].
+pub fn consolidate(snapshots: &[Db], rally_title: &str) -> Option<RallyResults> {
+    let stages = snapshots
+        .iter()
+        .rev()
+        .find_map(|db| db.rally_results(rally_title))?
+        .stages
+        .clone();
+    let mut driver_results: BTreeMap<String, Vec<Option<StageResult>>> = BTreeMap::new();
+    for results in snapshots
+        .iter()
+        .filter_map(|db| db.rally_results(rally_title))
+    {
+        let stage_idxs = stages
+            .iter()
+            .map(|stage| results.stage_index(stage))
+            .collect_vec();
+        for driver in &results.driver_results {
+            let merged = driver_results
+                .entry(driver.name.clone())
+                .or_insert_with(|| vec![None; stages.len()]);
+            for (merged, stage_idx) in merged.iter_mut().zip(&stage_idxs) {
+                if let Some(stage_result) = stage_idx.and_then(|i| driver.stages.get(i)?.as_ref()) {
+                    keep_fastest(merged, stage_result);
+                }
+            }
+        }
+    }
+
+    rank_locally(&mut driver_results, stages.len());
+    Some(RallyResults::from_driver_results(stages, driver_results))
+}
+
+/// Keep the faster of the merged and the new time on each stage.
+fn merge_fastest(merged: &mut [Option<StageResult>], stage_results: &[Option<StageResult>]) {
+    for (merged, stage_result) in merged.iter_mut().zip(stage_results) {
+        if let Some(stage_result) = stage_result {
+            keep_fastest(merged, stage_result);
+        }
+    }
+}
+
+/// Keep the faster of the merged and the new time.
+fn keep_fastest(merged: &mut Option<StageResult>, stage_result: &StageResult) {
+    if merged
+        .as_ref()
+        .is_none_or(|merged| stage_result.time_ms < merged.time_ms)
+    {
+        *merged = Some(stage_result.clone());
+    }
+}
+
+/// Recompute the local ranks on each stage from the times.
+fn rank_locally(driver_results: &mut BTreeMap<String, Vec<Option<StageResult>>>, stages: usize) {
+    // Equal times are ranked by name, since the drivers are sorted by name
+    for stage_idx in 0..stages {
+        let ranked = driver_results
+            .values_mut()
+            .filter_map(|stages| stages[stage_idx].as_mut())
+            .sorted_by_key(|stage_result| stage_result.time_ms);
+        for (rank, stage_result) in ranked.enumerate() {
+            stage_result.local_rank = rank + 1;
+        }
+    }
+}
+
+/// When the fastest time on each stage was set, according to the snapshots.
+pub struct StageRecords {
+    // { (rally title, stage key) => (fastest time, first seen) }
+    records: BTreeMap<(String, String), (usize, i64)>,
+}
+
+impl StageRecords {
+    /// `snapshots` should be sorted oldest first, like from [`load_snapshots`].
+    pub fn new(snapshots: &[(i64, Db)]) -> Self {
+        let mut records = BTreeMap::new();
+        for (ts, db) in snapshots {
+            for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+                for (stage, stage_results) in results.stages.iter().zip(&results.stage_results) {
+                    let Some(fastest) = stage_results.iter().map(|(_, r)| r.time_ms).min() else {
+                        continue;
+                    };
+                    records
+                        .entry((rally.title.clone(), stage_key(stage)))
+                        .and_modify(|record: &mut (usize, i64)| {
+                            if fastest < record.0 {
+                                *record = (fastest, *ts);
+                            }
+                        })
+                        .or_insert((fastest, *ts));
+                }
+            }
+        }
+        Self { records }
+    }
+
+    /// When the fastest time on a stage was first seen.
+    pub fn set_at(&self, rally_title: &str, stage: &StageWithLeaderboard) -> Option<i64> {
+        self.records
+            .get(&(rally_title.to_string(), stage_key(stage)))
+            .map(|(_, ts)| *ts)
+    }
+}
+
+/// Each driver's fastest time on each stage over all snapshots.
+pub struct PersonalBests {
+    // { (rally title, stage key, driver name) => fastest time }
+    bests: BTreeMap<(String, String, String), usize>,
+}
+
+impl PersonalBests {
+    pub fn new(snapshots: &[(i64, Db)]) -> Self {
+        let mut bests = BTreeMap::new();
+        for (_, db) in snapshots {
+            for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+                for (stage, stage_results) in results.stages.iter().zip(&results.stage_results) {
+                    for (name, stage_result) in stage_results {
+                        bests
+                            .entry((rally.title.clone(), stage_key(stage), name.clone()))
+                            .and_modify(|best: &mut usize| {
+                                *best = (*best).min(stage_result.time_ms)
+                            })
+                            .or_insert(stage_result.time_ms);
+                    }
+                }
+            }
+        }
+        Self { bests }
+    }
+
+    /// A driver's fastest time on a stage in any snapshot.
+    pub fn get(
+        &self,
+        rally_title: &str,
+        stage: &StageWithLeaderboard,
+        name: &str,
+    ) -> Option<usize> {
+        self.bests
+            .get(&(rally_title.to_string(), stage_key(stage), name.to_string()))
+            .copied()
+    }
+
+    /// The sum of a driver's fastest times on each of a rally's `stages`, from
+    /// any snapshot. `None` if they've never driven one of them.
+    pub fn theoretical_best(
+        &self,
+        rally_title: &str,
+        stages: &[StageWithLeaderboard],
+        name: &str,
+    ) -> Option<usize> {
+        stages
+            .iter()
+            .map(|stage| self.get(rally_title, stage, name))
+            .sum()
+    }
+}
+
+/// A driver's time on a stage in each snapshot they have one in, oldest
+/// first.
+///
+/// Stages are matched by [`stage_key`] rather than index, so it still works
+/// for snapshots from before stages were added to or removed from the rally.
+/// Snapshots where the driver doesn't have a time are skipped.
+pub fn progression(
+    snapshots: &[(i64, Db)],
+    rally_title: &str,
+    stage: &StageWithLeaderboard,
+    driver: &str,
+) -> Vec<(i64, usize)> {
+    snapshots
+        .iter()
+        .filter_map(|(ts, db)| {
+            let time = db
+                .rally_results(rally_title)?
+                .stage_result(driver, stage)?
+                .time_ms;
+            Some((*ts, time))
+        })
+        .collect()
+}
+
+/// Drivers who haven't set a new time in the last `runs` snapshots.
+///
+/// `snapshots` should be sorted oldest first, like from [`load_snapshots`],
+/// and end with the current run. Nobody is inactive until there are more than
+/// `runs` snapshots.
+pub fn inactive_drivers(snapshots: &[(i64, Db)], runs: usize) -> BTreeSet<String> {
+    let Some((_, latest)) = snapshots.last() else {
+        return BTreeSet::new();
+    };
+    let Some(cutoff) = snapshots
+        .len()
+        .checked_sub(runs + 1)
+        .map(|idx| snapshots[idx].0)
+    else {
+        return BTreeSet::new();
+    };
+    latest
+        .user_names
+        .iter()
+        .filter(|name| {
+            // Times from before we kept track count as old
+            let last_new_time = latest
+                .results
+                .iter()
+                .flat_map(|results| &results.driver_results)
+                .filter(|driver| &driver.name == *name)
+                .flat_map(|driver| driver.stages.iter().flatten())
+                .filter_map(|stage_result| stage_result.observed_at)
+                .max();
+            last_new_time.is_none_or(|ts| ts <= cutoff)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{db, stage, stage_result};
+
+    /// Results on `stages` with one time per stage for each driver.
+    fn results_on(stages: &[usize], drivers: &[(&str, &[Option<usize>])]) -> RallyResults {
+        let driver_results = drivers
+            .iter()
+            .map(|(name, times)| {
+                let times = times
+                    .iter()
+                    .map(|time| time.map(|time| stage_result(time, 1)))
+                    .collect();
+                (name.to_string(), times)
+            })
+            .collect();
+        RallyResults::from_driver_results(
+            stages.iter().copied().map(stage).collect(),
+            driver_results,
+        )
+    }
+
+    #[test]
+    fn consolidate_matches_stages_by_identity() {
+        let old = db(
+            "test",
+            results_on(&[1, 2], &[("alice", &[Some(61_000), Some(62_000)])]),
+        );
+        // Stage 1 was dropped and stage 3 added
+        let new = db(
+            "test",
+            results_on(&[2, 3], &[("alice", &[Some(63_000), None])]),
+        );
+
+        let results = consolidate(&[old, new], "test").unwrap();
+        assert_eq!(results.stages.len(), 2);
+        assert_eq!(
+            results.stage_result("alice", &stage(2)).unwrap().time_ms,
+            62_000
+        );
+        assert!(results.stage_result("alice", &stage(3)).is_none());
+    }
+
+    #[test]
+    fn bests_and_records_match_stages_by_identity() {
+        let old = db(
+            "test",
+            results_on(&[1, 2], &[("alice", &[Some(61_000), Some(62_000)])]),
+        );
+        let new = db(
+            "test",
+            results_on(&[2, 1], &[("alice", &[Some(63_000), Some(60_000)])]),
+        );
+        let snapshots = [(100, old), (200, new)];
+
+        let bests = PersonalBests::new(&snapshots);
+        assert_eq!(bests.get("test", &stage(1), "alice"), Some(60_000));
+        assert_eq!(bests.get("test", &stage(2), "alice"), Some(62_000));
+        assert_eq!(
+            bests.theoretical_best("test", &[stage(1), stage(2)], "alice"),
+            Some(122_000)
+        );
+        assert_eq!(
+            bests.theoretical_best("test", &[stage(1), stage(3)], "alice"),
+            None
+        );
+
+        let records = StageRecords::new(&snapshots);
+        assert_eq!(records.set_at("test", &stage(1)), Some(200));
+        assert_eq!(records.set_at("test", &stage(2)), Some(100));
+        assert_eq!(
+            progression(&snapshots, "test", &stage(2), "alice"),
+            [(100, 62_000), (200, 63_000)]
+        );
+    }
+
+    /// An empty directory of its own for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aor-utils-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn snapshot_paths_sort_numerically() {
+        let dir = temp_dir("snapshot-paths");
+        for name in ["1000000000.ron", "999999999.ron", "1000000001.ron"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let timestamps = snapshot_paths(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(ts, _)| ts)
+            .collect_vec();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(timestamps, [999_999_999, 1_000_000_000, 1_000_000_001]);
+    }
+
+    #[test]
+    fn half_written_snapshots_are_never_latest() {
+        let dir = temp_dir("half-written");
+        let snapshot = db(
+            "test",
+            crate::test_utils::rally_results(&[("alice", &[Some(60_000)])]),
+        );
+        std::fs::write(dir.join("1000.ron"), ron::to_string(&snapshot).unwrap()).unwrap();
+        // Killed halfway through writing a newer snapshot
+        std::fs::write(dir.join("2000.ron.tmp"), "(rallys: [(").unwrap();
+        let latest = latest_snapshot_at(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (ts, latest) = latest.unwrap();
+        assert_eq!(ts, 1000);
+        assert_eq!(latest.rallys[0].title, "test");
+    }
+
+    #[test]
+    fn records_are_dated_by_the_snapshot_that_set_them() {
+        const DAY: i64 = 60 * 60 * 24;
+        let snapshot = |times: &[(&str, &[Option<usize>])]| {
+            db("test", crate::test_utils::rally_results(times))
+        };
+        let snapshots = [
+            (0, snapshot(&[("alice", &[Some(60_000), Some(70_000)])])),
+            (
+                3 * DAY,
+                snapshot(&[
+                    ("alice", &[Some(60_000), Some(70_000)]),
+                    ("bob", &[Some(61_000), None]),
+                ]),
+            ),
+            (
+                5 * DAY,
+                snapshot(&[
+                    ("alice", &[Some(60_000), Some(70_000)]),
+                    ("bob", &[Some(59_000), None]),
+                ]),
+            ),
+            (
+                9 * DAY,
+                snapshot(&[
+                    ("alice", &[Some(59_000), Some(69_000)]),
+                    ("bob", &[Some(59_000), None]),
+                ]),
+            ),
+        ];
+        let records = StageRecords::new(&snapshots);
+        // Equaling a record doesn't reset it
+        assert_eq!(records.set_at("test", &stage(1)), Some(5 * DAY));
+        // Fell in the newest snapshot
+        assert_eq!(records.set_at("test", &stage(2)), Some(9 * DAY));
+        assert_eq!(records.set_at("test", &stage(3)), None);
+        assert_eq!(records.set_at("other", &stage(1)), None);
+
+        let records = StageRecords::new(&snapshots[..3]);
+        assert_eq!(records.set_at("test", &stage(2)), Some(0));
+    }
+
+    #[test]
+    fn drivers_without_new_times_are_inactive() {
+        let mut latest = db(
+            "test",
+            crate::test_utils::rally_results(&[
+                ("alice", &[Some(60_000), Some(70_000)]),
+                ("bob", &[Some(61_000), Some(71_000)]),
+                ("carol", &[Some(62_000), None]),
+            ]),
+        );
+        // alice improved on the second stage in the third run, bob in the
+        // second run and carol's times are from before we kept track
+        for driver in &mut latest.results[0].driver_results {
+            let observed_at = match driver.name.as_str() {
+                "alice" => [Some(100), Some(300)],
+                "bob" => [Some(100), Some(200)],
+                _ => [None, None],
+            };
+            for (stage, observed_at) in driver.stages.iter_mut().zip(observed_at) {
+                if let Some(stage) = stage {
+                    stage.observed_at = observed_at;
+                }
+            }
+        }
+        let empty = || db("test", crate::test_utils::rally_results(&[]));
+        let snapshots = [
+            (100, empty()),
+            (200, empty()),
+            (300, empty()),
+            (400, latest),
+        ];
+
+        assert_eq!(
+            inactive_drivers(&snapshots, 2),
+            BTreeSet::from(["bob".to_string(), "carol".to_string()])
+        );
+        assert_eq!(
+            inactive_drivers(&snapshots, 3),
+            BTreeSet::from(["carol".to_string()])
+        );
+        // Not enough history to tell
+        assert!(inactive_drivers(&snapshots, 4).is_empty());
+    }
+
+    #[test]
+    fn consolidate_merges_partial_snapshots() {
+        let first = db(
+            "test",
+            crate::test_utils::rally_results(&[
+                ("alice", &[Some(61_000), None]),
+                ("bob", &[Some(60_000), None]),
+            ]),
+        );
+        let second = db(
+            "test",
+            crate::test_utils::rally_results(&[
+                ("alice", &[None, Some(70_000)]),
+                ("bob", &[Some(62_000), Some(71_000)]),
+            ]),
+        );
+
+        let results = consolidate(&[first, second], "test").unwrap();
+        let times = |name: &str| {
+            results
+                .driver_results
+                .iter()
+                .find(|driver| driver.name == name)
+                .unwrap()
+                .stages
+                .iter()
+                .map(|stage_result| {
+                    let stage_result = stage_result.as_ref().unwrap();
+                    (stage_result.time_ms, stage_result.local_rank)
+                })
+                .collect_vec()
+        };
+        // Both are full times now, with bob's older and faster time kept
+        assert_eq!(times("alice"), [(61_000, 2), (70_000, 1)]);
+        assert_eq!(times("bob"), [(60_000, 1), (71_000, 2)]);
+        assert!(consolidate(&[], "test").is_none());
+    }
+}
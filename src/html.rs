@@ -0,0 +1,142 @@
+use maud::{PreEscaped, html};
+
+/// A full page with the shared `<head>` and a footer saying when it was
+/// generated.
+pub fn html_page<'a>(
+    header: &str,
+    font_stylesheets: &[String],
+    body: impl IntoIterator<Item = &'a PreEscaped<String>>,
+) -> PreEscaped<String> {
+    html_page_updated_at(header, font_stylesheets, body, chrono::Utc::now())
+}
+
+/// Like [`html_page`], but the footer says it was updated at `updated_at`, so
+/// the page only changes when its contents do.
+pub fn html_page_updated_at<'a>(
+    header: &str,
+    font_stylesheets: &[String],
+    body: impl IntoIterator<Item = &'a PreEscaped<String>>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+) -> PreEscaped<String> {
+    let google_fonts = font_stylesheets
+        .iter()
+        .any(|url| url.starts_with("https://fonts.googleapis.com/"));
+    html!(
+        (maud::DOCTYPE)
+        html {
+            head {
+                link rel="stylesheet" href="/style.css";
+                @if google_fonts {
+                    link rel="preconnect" href="https://fonts.googleapis.com";
+                    link rel="preconnect" href="https://fonts.gstatic.com" crossorigin;
+                }
+                @for url in font_stylesheets {
+                    link rel="stylesheet" href=(url);
+                }
+            }
+
+            body {
+                h1 { (header) }
+
+                @for part in body {
+                    (part)
+                }
+
+                p {
+                    "last updated: " (updated_at.format("%F %R %Z"))
+                }
+            }
+        }
+    )
+}
+
+/// A name as used in file names and links: lowercase `a-z`, `0-9` and `-`.
+///
+/// Spaces become dashes, accented letters lose their accents and other
+/// characters are dropped. Since that could make different names end up the
+/// same, like `Alice` and `alice` or `a b` and `a-b`, every name that had to
+/// change gets a short hash of the whole name at the end.
+pub fn url_safe(s: &str) -> String {
+    let mut slug = String::with_capacity(s.len());
+    for c in s.to_lowercase().chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '-' => slug.push(c),
+            ' ' => slug.push('-'),
+            _ => {
+                if let Some(c) = unaccented(c) {
+                    slug.push(c);
+                }
+            }
+        }
+    }
+    if slug != s {
+        let hash = format!("{:x}", md5::compute(s.as_bytes()));
+        slug = format!("{slug}-{}", &hash[..6]);
+    }
+    slug
+}
+
+/// The letter without its accent, for the accented letters we're likely to
+/// see in names.
+fn unaccented(c: char) -> Option<char> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'æ' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_safe_keeps_safe_names() {
+        assert_eq!(url_safe("alice"), "alice");
+        assert_eq!(url_safe("a-b"), "a-b");
+        assert_eq!(url_safe("kenya-1"), "kenya-1");
+    }
+
+    #[test]
+    fn url_safe_is_stable() {
+        assert_eq!(url_safe("Alice"), url_safe("Alice"));
+        assert_eq!(
+            url_safe("Alice"),
+            format!("alice-{}", &format!("{:x}", md5::compute("Alice"))[..6])
+        );
+        assert!(url_safe("Émile Ö").starts_with("emile-o-"));
+    }
+
+    #[test]
+    fn url_safe_avoids_collisions() {
+        for [a, b] in [
+            ["Alice", "alice"],
+            ["a b", "a-b"],
+            ["Émile", "emile"],
+            ["bob!", "bob?"],
+        ] {
+            assert_ne!(url_safe(a), url_safe(b), "{a:?} and {b:?}");
+        }
+    }
+
+    #[test]
+    fn no_font_stylesheets_means_no_google_fonts() {
+        let body = html!(p { "hello" });
+        let page = html_page("test", &[], [&body]).into_string();
+        assert!(!page.contains("googleapis"), "{page}");
+        assert!(!page.contains("gstatic"), "{page}");
+
+        let fonts = ["https://fonts.googleapis.com/css2?family=Ubuntu+Mono".to_string()];
+        let page = html_page("test", &fonts, [&body]).into_string();
+        assert!(page.contains(r#"<link rel="preconnect" href="https://fonts.googleapis.com">"#));
+        assert!(page.contains(
+            r#"<link rel="stylesheet" href="https://fonts.googleapis.com/css2?family=Ubuntu+Mono">"#
+        ));
+    }
+}
@@ -1,71 +1,298 @@
+use std::io::{BufReader, IsTerminal as _};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::Itertools as _;
 use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+/// Why a URL couldn't be downloaded.
+#[derive(Debug, Snafu)]
+pub enum DownloadError {
+    #[snafu(display("{url} responded with {status}"))]
+    Status { url: String, status: u16 },
+    #[snafu(display("Couldn't reach {url}\n{source}"))]
+    Transport { url: String, source: ureq::Error },
+    #[snafu(display("Couldn't parse the response from {url}\n{source}"))]
+    Parse {
+        url: String,
+        source: serde_json::Error,
+    },
+    /// The body is larger than [`max_body_size`].
+    #[snafu(display("The response from {url} is larger than {limit} bytes"))]
+    TooLarge { url: String, limit: u64 },
+}
 
 enum CacheResult<T> {
     CacheHit(T),
     Miss,
 }
 
-fn try_get_cache<T>(url: &str) -> CacheResult<T>
+/// The directory responses are cached in.
+///
+/// With `AOR_UTILS_CACHE_RUN=<name>` it's `cache/run-<name>`, which keeps the
+/// exact responses of a run apart from the shared cache so it can be
+/// replayed later.
+fn cache_dir() -> PathBuf {
+    run_cache_dir(
+        Path::new("cache"),
+        std::env::var("AOR_UTILS_CACHE_RUN").ok().as_deref(),
+    )
+}
+
+/// `root/run-<run>` for a named run, otherwise `root` itself.
+fn run_cache_dir(root: &Path, run: Option<&str>) -> PathBuf {
+    match run {
+        Some(run) => root.join(format!("run-{run}")),
+        None => root.to_path_buf(),
+    }
+}
+
+/// Whether responses are cached, with `AOR_UTILS_CACHE=1` or
+/// `AOR_UTILS_CACHE_RUN`.
+pub fn cache_enabled() -> bool {
+    std::env::var("AOR_UTILS_CACHE").ok() == Some("1".to_string())
+        || std::env::var("AOR_UTILS_CACHE_RUN").is_ok()
+}
+
+fn cache_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(format!("{:?}", md5::compute(url.as_bytes())))
+}
+
+/// How long cached responses are used for, set in seconds with
+/// `AOR_UTILS_CACHE_TTL`. Forever if unset.
+fn cache_ttl() -> Option<Duration> {
+    std::env::var("AOR_UTILS_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Whether a cache entry is older than the TTL. Entries whose age can't be
+/// told are never stale.
+fn is_stale(p: &Path, ttl: Duration) -> bool {
+    std::fs::metadata(p)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > ttl)
+}
+
+/// A corrupt or unreadable cache entry is a miss, so it gets downloaded again.
+/// So is an entry older than [`cache_ttl`].
+fn try_get_cache<T>(dir: &Path, url: &str) -> CacheResult<T>
 where
     T: for<'a> Deserialize<'a>,
 {
-    let p = format!("cache/{:?}", md5::compute(url.as_bytes()));
-    if !std::fs::exists(&p).unwrap() {
+    let p = cache_path(dir, url);
+    if let Some(ttl) = cache_ttl()
+        && is_stale(&p, ttl)
+    {
         return CacheResult::Miss;
     }
-    CacheResult::CacheHit(serde_json::from_str(&std::fs::read_to_string(p).unwrap()).unwrap())
+    let s = match std::fs::read_to_string(&p) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return CacheResult::Miss,
+        Err(e) => {
+            tracing::warn!("couldn't read {}, downloading again\n{e}", p.display());
+            return CacheResult::Miss;
+        }
+    };
+    match serde_json::from_str(&s) {
+        Ok(t) => CacheResult::CacheHit(t),
+        Err(e) => {
+            tracing::warn!("couldn't parse {}, downloading again\n{e}", p.display());
+            CacheResult::Miss
+        }
+    }
 }
 
-fn insert_cache<T>(url: &str, t: &T)
+fn insert_cache<T>(dir: &Path, url: &str, t: &T)
 where
     T: Serialize,
 {
-    let p = format!("cache/{:?}", md5::compute(url.as_bytes()));
-    std::fs::write(p, serde_json::to_string_pretty(t).unwrap()).unwrap();
+    let p = cache_path(dir, url);
+    let written = serde_json::to_string_pretty(t)
+        .map_err(std::io::Error::from)
+        .and_then(|s| std::fs::write(&p, s));
+    if let Err(e) = written {
+        tracing::warn!("couldn't write {}\n{e}", p.display());
+    }
 }
 
-/// Download and JSON-parse the results for some URLs.
-pub fn download_all<T: for<'a> Deserialize<'a> + Serialize + Clone>(
-    urls: &[impl AsRef<str>],
-) -> Vec<Option<T>> {
-    let cache = std::env::var("AOR_UTILS_CACHE").ok() == Some("1".to_string());
-    if cache {
-        std::fs::create_dir_all("cache").unwrap();
+/// The largest response body we're willing to parse, in bytes. Set with
+/// `AOR_UTILS_MAX_BODY`, defaulting to 64 MiB which no friends leaderboard
+/// comes close to.
+fn max_body_size() -> u64 {
+    std::env::var("AOR_UTILS_MAX_BODY")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(64 * 1024 * 1024)
+}
+
+/// How many times to retry a failed download, set with `AOR_UTILS_RETRIES`.
+fn retries() -> u32 {
+    std::env::var("AOR_UTILS_RETRIES")
+        .ok()
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(3)
+}
+
+/// How long to wait before the first retry, doubling for every retry after
+/// that. Set with `AOR_UTILS_RETRY_DELAY_MS`.
+fn retry_delay() -> Duration {
+    std::env::var("AOR_UTILS_RETRY_DELAY_MS")
+        .ok()
+        .and_then(|delay| delay.parse().ok())
+        .map_or(Duration::from_millis(200), Duration::from_millis)
+}
+
+/// GET `url`, retrying server errors and failed connections with exponential
+/// backoff. Client errors aren't retried.
+fn get_with_retries(
+    agent: &ureq::Agent,
+    url: &str,
+    retries: u32,
+    delay: Duration,
+) -> Result<ureq::http::Response<ureq::Body>, DownloadError> {
+    let mut attempt = 0;
+    loop {
+        let e = match agent.get(url).call() {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+        let retryable = !matches!(e, ureq::Error::StatusCode(status) if status < 500);
+        if retryable && attempt < retries {
+            std::thread::sleep(delay * 2u32.pow(attempt));
+            attempt += 1;
+            continue;
+        }
+        let url = url.to_string();
+        return Err(match e {
+            ureq::Error::StatusCode(status) => DownloadError::Status { url, status },
+            source => DownloadError::Transport { url, source },
+        });
     }
+}
 
+/// Whether to leave out the progress bar, with `AOR_UTILS_QUIET=1`.
+fn quiet() -> bool {
+    std::env::var("AOR_UTILS_QUIET").ok() == Some("1".to_string())
+}
+
+/// A progress bar for downloading `len` URLs, possibly over several calls to
+/// [`download_all`]. Hidden when stderr isn't a terminal, so logs from cron
+/// don't fill up with redraws, or when [`quiet`].
+pub fn progress_bar(len: usize) -> ProgressBar {
+    if quiet() || !std::io::stderr().is_terminal() {
+        return ProgressBar::with_draw_target(Some(len as _), ProgressDrawTarget::hidden());
+    }
     let progress_style = ProgressStyle::default_bar()
         .template("{bar} {msg} ({pos}/{len}) {elapsed}")
         .unwrap()
         .progress_chars("#|-");
-    let progress = ProgressBar::new(urls.len() as _).with_style(progress_style);
+    let progress = ProgressBar::new(len as _).with_style(progress_style);
     progress.enable_steady_tick(Duration::from_millis(100));
+    progress
+}
+
+/// The error for a body that couldn't be parsed: [`DownloadError::TooLarge`]
+/// if the body reader hit the size limit, otherwise [`DownloadError::Parse`].
+///
+/// The reader's error reaches us as the io::Error that serde_json wraps, with
+/// ureq's error inside of it.
+fn parse_error(url: &str, source: serde_json::Error, limit: u64) -> DownloadError {
+    let url = url.to_string();
+    if !source.is_io() {
+        return DownloadError::Parse { url, source };
+    }
+    let e = std::io::Error::from(source);
+    if e.get_ref()
+        .and_then(|e| e.downcast_ref::<ureq::Error>())
+        .is_some_and(|e| matches!(e, ureq::Error::BodyExceedsLimit(_)))
+    {
+        DownloadError::TooLarge { url, limit }
+    } else {
+        DownloadError::Parse {
+            url,
+            source: serde_json::Error::io(e),
+        }
+    }
+}
+
+/// How [`download_all`] downloads, from the environment.
+struct DownloadSettings {
+    /// The directory to cache responses in, if they're cached.
+    cache: Option<PathBuf>,
+    max_body_size: u64,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl DownloadSettings {
+    fn from_env() -> Self {
+        Self {
+            cache: cache_enabled().then(cache_dir),
+            max_body_size: max_body_size(),
+            retries: retries(),
+            retry_delay: retry_delay(),
+        }
+    }
+}
+
+/// Download and JSON-parse the results for some URLs.
+pub fn download_all<T: for<'a> Deserialize<'a> + Serialize + Clone>(
+    urls: &[impl AsRef<str>],
+    progress: &ProgressBar,
+) -> Vec<Result<T, DownloadError>> {
+    download_all_with(urls, progress, &DownloadSettings::from_env())
+}
+
+fn download_all_with<T: for<'a> Deserialize<'a> + Serialize + Clone>(
+    urls: &[impl AsRef<str>],
+    progress: &ProgressBar,
+    settings: &DownloadSettings,
+) -> Vec<Result<T, DownloadError>> {
+    let DownloadSettings {
+        ref cache,
+        max_body_size,
+        retries,
+        retry_delay,
+    } = *settings;
+    if let Some(dir) = cache
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        tracing::warn!("couldn't create {}\n{e}", dir.display());
+    }
 
     let agent = ureq::agent();
     urls.iter()
         .map(|url| {
-            let (url, cache_hit) = if cache {
-                (url, try_get_cache::<T>(url.as_ref()))
+            let (url, cache_hit) = if let Some(dir) = cache {
+                (url, try_get_cache::<T>(dir, url.as_ref()))
             } else {
                 (url, CacheResult::Miss)
             };
             match cache_hit {
-                CacheResult::CacheHit(x) => Some(x),
+                CacheResult::CacheHit(x) => Ok(x),
                 CacheResult::Miss => {
-                    let resp = agent
-                        .get(url.as_ref())
-                        .call()
-                        .ok()?
-                        .body_mut()
-                        .read_json()
-                        .ok()?;
-                    if cache {
-                        insert_cache(url.as_ref(), &resp);
+                    // Parsed from the body reader so that reading stops at the
+                    // size limit. The parsed response still holds every
+                    // entry, so this doesn't save memory over buffering the
+                    // body first.
+                    let resp = serde_json::from_reader(BufReader::new(
+                        get_with_retries(&agent, url.as_ref(), retries, retry_delay)?
+                            .body_mut()
+                            .with_config()
+                            .limit(max_body_size)
+                            .reader(),
+                    ))
+                    .map_err(|source| parse_error(url.as_ref(), source, max_body_size))?;
+                    if let Some(dir) = cache {
+                        insert_cache(dir, url.as_ref(), &resp);
                     }
-                    Some(resp)
+                    Ok(resp)
                 }
             }
         })
@@ -74,3 +301,126 @@ pub fn download_all<T: for<'a> Deserialize<'a> + Serialize + Clone>(
         })
         .collect_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead as _, Write as _};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serve `responses` as (status, body), one per request, and return the
+    /// server's URL.
+    fn serve(responses: Vec<(u16, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while request.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    fn settings() -> DownloadSettings {
+        DownloadSettings {
+            cache: None,
+            max_body_size: 1024,
+            retries: 0,
+            retry_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn oversized_bodies_are_too_large() {
+        let body = format!("[{}1]", "1,".repeat(1024));
+        let url = serve(vec![(200, body)]);
+        let results = download_all_with::<Vec<u32>>(&[url], &ProgressBar::hidden(), &settings());
+        assert!(
+            matches!(results[0], Err(DownloadError::TooLarge { limit: 1024, .. })),
+            "{results:?}"
+        );
+    }
+
+    #[test]
+    fn small_bodies_are_parsed() {
+        let url = serve(vec![(200, "[1,2,3]".to_string())]);
+        let results = download_all_with::<Vec<u32>>(&[url], &ProgressBar::hidden(), &settings());
+        assert_eq!(results[0].as_ref().unwrap(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn malformed_small_bodies_are_parse_errors() {
+        let url = serve(vec![(200, "[1,2,".to_string())]);
+        let results = download_all_with::<Vec<u32>>(&[url], &ProgressBar::hidden(), &settings());
+        assert!(
+            matches!(results[0], Err(DownloadError::Parse { .. })),
+            "{results:?}"
+        );
+    }
+
+    #[test]
+    fn a_named_run_has_a_cache_of_its_own() {
+        let root = std::env::temp_dir().join(format!("aor-utils-cache-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = run_cache_dir(&root, Some("debug"));
+        assert_eq!(dir, root.join("run-debug"));
+        assert_eq!(run_cache_dir(&root, None), root);
+
+        let url = serve(vec![(200, "[1,2,3]".to_string())]);
+        let download = |dir: &Path| {
+            let settings = DownloadSettings {
+                cache: Some(dir.to_path_buf()),
+                ..settings()
+            };
+            download_all_with::<Vec<u32>>(&[&url], &ProgressBar::hidden(), &settings)
+                .pop()
+                .unwrap()
+        };
+        assert_eq!(download(&dir).unwrap(), [1, 2, 3]);
+        assert!(cache_path(&dir, &url).exists());
+        // The server is gone, so these can only come from the caches
+        let replayed = download(&dir);
+        let shared = download(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(replayed.unwrap(), [1, 2, 3]);
+        assert!(shared.is_err());
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let unavailable = || (503, "unavailable".to_string());
+        let url = serve(vec![unavailable(), unavailable(), (200, "[1]".to_string())]);
+        let progress = ProgressBar::hidden();
+        let patient = DownloadSettings {
+            retries: 2,
+            ..settings()
+        };
+        let results = download_all_with::<Vec<u32>>(&[url], &progress, &patient);
+        assert_eq!(results[0].as_ref().unwrap(), &[1]);
+        assert_eq!(progress.position(), 1);
+
+        // One retry isn't enough for two failures
+        let url = serve(vec![unavailable(), unavailable(), (200, "[1]".to_string())]);
+        let impatient = DownloadSettings {
+            retries: 1,
+            ..settings()
+        };
+        let results = download_all_with::<Vec<u32>>(&[url], &ProgressBar::hidden(), &impatient);
+        assert!(
+            matches!(results[0], Err(DownloadError::Status { status: 503, .. })),
+            "{results:?}"
+        );
+    }
+}
@@ -1,15 +1,38 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use art_of_rally_leaderboard_api::{
-    Area, Direction, Filter, Group, Leaderboard, Platform, Response, Stage, Weather,
+    Area, Direction, Filter, Group, Leaderboard, LeaderboardEntry, Platform, Response, Stage,
+    Weather, car_name,
 };
+use indicatif::ProgressBar;
 use itertools::Itertools;
+use serde::de::IntoDeserializer as _;
 use serde::{Deserialize, Serialize};
-use snafu::Whatever;
+use snafu::{ResultExt as _, Whatever, whatever};
+
+use crate::config::{Config, RallyConfig};
+use crate::history::{DB_VERSION, Db};
+use crate::table_utils::Precision;
 
 pub mod config;
+pub mod history;
+pub mod html;
 pub mod http;
 pub mod table_utils;
+#[doc(hidden)]
+pub mod test_utils;
+
+/// World ranks outside of this range are the API's way of saying "unranked".
+pub const VALID_WORLD_RANKS: RangeInclusive<usize> = 1..=100_000_000;
+
+/// Stage times outside of this range, in milliseconds, probably mean that the
+/// API's `score` isn't in milliseconds anymore.
+pub const PLAUSIBLE_STAGE_TIMES_MS: RangeInclusive<usize> = 10_000..=3_600_000;
 
 pub type StageWithLeaderboard = (Stage, Group, Weather);
 
@@ -17,6 +40,96 @@ pub type StageWithLeaderboard = (Stage, Group, Weather);
 pub struct Rally {
     pub title: String,
     pub stages: Vec<StageWithLeaderboard>,
+    /// How precisely times are shown for this rally.
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// A stage's name, with the direction spelled out for reverse stages so they
+/// don't share names (and pages) with the forward ones.
+pub struct StageName<'s>(pub &'s Stage);
+
+impl fmt::Display for StageName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.direction {
+            Direction::Forward => write!(f, "{}", self.0),
+            Direction::Reverse => write!(f, "{} reverse", self.0),
+        }
+    }
+}
+
+/// What identifies a stage across snapshots, to match up the stages of
+/// results where the rally's stages have changed in between. The group is left
+/// out since it's the same for every stage of a rally.
+pub fn stage_key((stage, _group, weather): &StageWithLeaderboard) -> String {
+    format!("{} {weather}", StageName(stage))
+}
+
+/// The numbers of an area's stages. Every area in the game has six, but
+/// nothing else assumes that, so this is the only place to change if one
+/// doesn't.
+pub fn stage_numbers(_area: Area) -> RangeInclusive<usize> {
+    1..=6
+}
+
+impl Rally {
+    /// Build a rally from the config, checking that it's a rally that exists.
+    pub fn from_config(entry: &RallyConfig) -> Result<Rally, Whatever> {
+        if entry.stages.is_empty() {
+            whatever!("Rally {:?} has no stages", entry.title);
+        }
+        let stage_numbers = stage_numbers(entry.area);
+        if let Some(stage_number) = entry
+            .stages
+            .iter()
+            .find(|stage_number| !stage_numbers.contains(stage_number))
+        {
+            whatever!(
+                "Rally {:?} has stage {stage_number}, but stages in {:?} are numbered {}-{}",
+                entry.title,
+                entry.area,
+                stage_numbers.start(),
+                stage_numbers.end()
+            );
+        }
+        if let Some(stage_number) = entry.stages.iter().duplicates().next() {
+            whatever!(
+                "Rally {:?} has stage {stage_number} more than once",
+                entry.title
+            );
+        }
+
+        let directions = if entry.directions.is_empty() {
+            std::slice::from_ref(&entry.direction)
+        } else {
+            entry.directions.as_slice()
+        };
+        let weathers = if entry.weathers.is_empty() {
+            std::slice::from_ref(&entry.weather)
+        } else {
+            entry.weathers.as_slice()
+        };
+        Ok(Rally {
+            title: entry.title.clone(),
+            stages: directions
+                .iter()
+                .cartesian_product(weathers)
+                .cartesian_product(&entry.stages)
+                .map(|((&direction, &weather), &stage_number)| {
+                    (
+                        Stage {
+                            area: entry.area,
+                            stage_number: stage_number as _,
+                            direction,
+                        },
+                        entry.group,
+                        weather,
+                    )
+                })
+                .collect(),
+            precision: entry.precision,
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -25,6 +138,14 @@ pub struct DriverResult {
     pub stages: Vec<Option<StageResult>>,
 }
 
+impl DriverResult {
+    /// How many stages have been finished, out of how many.
+    pub fn completion(&self, total_stages: usize) -> (usize, usize) {
+        let finished = self.stages.iter().filter(|o| o.is_some()).count();
+        (finished, total_stages)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct RallyResults {
     pub stages: Vec<StageWithLeaderboard>,
@@ -39,56 +160,239 @@ pub struct StageResult {
     pub time_ms: usize,
     pub local_rank: usize,
     pub world_rank: Option<usize>,
+    /// How many entries the stage's world leaderboard had, when the API told
+    /// us. See [`world_top_percent`].
+    #[serde(default)]
+    pub world_entries: Option<usize>,
+    /// Unix timestamp of the first snapshot this time was seen in. `None` for
+    /// times from before we kept track.
+    #[serde(default)]
+    pub observed_at: Option<i64>,
+}
+
+/// How a result compares to a previous one. `Less` is a smaller number, so a
+/// faster time or a better rank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub time: Ordering,
+    pub local_rank: Ordering,
+    /// `Equal` unless both results have a world rank.
+    pub world_rank: Ordering,
+}
+
+impl StageResult {
+    pub fn change_since(&self, prev: &StageResult) -> Change {
+        Change {
+            time: self.time_ms.cmp(&prev.time_ms),
+            local_rank: self.local_rank.cmp(&prev.local_rank),
+            world_rank: match (self.world_rank, prev.world_rank) {
+                (Some(world_rank), Some(prev)) => world_rank.cmp(&prev),
+                _ => Ordering::Equal,
+            },
+        }
+    }
+}
+
+impl RallyResults {
+    /// Results from each driver's result on each stage.
+    pub fn from_driver_results(
+        stages: Vec<StageWithLeaderboard>,
+        driver_results: BTreeMap<String, Vec<Option<StageResult>>>,
+    ) -> RallyResults {
+        let mut stage_results = stages.iter().map(|_| Vec::new()).collect_vec();
+        for (driver, driver_stage_results) in &driver_results {
+            for (i, driver_stage_result) in driver_stage_results.iter().enumerate() {
+                let Some(driver_stage_result) = driver_stage_result else {
+                    continue;
+                };
+                stage_results[i].push((driver.clone(), driver_stage_result.clone()));
+            }
+        }
+        // Equal times are ordered by name, so the order is the same every run
+        for stage_result in &mut stage_results {
+            stage_result.sort_by(|(name1, x1), (name2, x2)| {
+                x1.time_ms.cmp(&x2.time_ms).then_with(|| name1.cmp(name2))
+            });
+        }
+
+        RallyResults {
+            stages,
+            driver_results: driver_results
+                .into_iter()
+                .map(|(name, stages)| DriverResult { name, stages })
+                .collect(),
+            stage_results,
+        }
+    }
+
+    /// Write the results as CSV, with one row per driver and the time in
+    /// milliseconds, car id, local rank and world rank for each stage. Stages
+    /// a driver hasn't driven are left empty.
+    pub fn to_csv(&self, mut w: impl std::io::Write) -> std::io::Result<()> {
+        let mut header = vec!["driver".to_string(), "total_ms".to_string()];
+        for (stage, _group, weather) in &self.stages {
+            let stage = format!("{} ({weather})", StageName(stage));
+            for column in ["time_ms", "car", "local_rank", "world_rank"] {
+                header.push(format!("{stage} {column}"));
+            }
+        }
+        writeln!(
+            w,
+            "{}",
+            header.iter().map(|cell| csv_escape(cell)).join(",")
+        )?;
+
+        for driver in &self.driver_results {
+            let total: usize = driver.stages.iter().flatten().map(|r| r.time_ms).sum();
+            let mut row = vec![csv_escape(&driver.name), total.to_string()];
+            for stage_result in &driver.stages {
+                match stage_result {
+                    Some(r) => row.extend([
+                        r.time_ms.to_string(),
+                        r.car.to_string(),
+                        r.local_rank.to_string(),
+                        r.world_rank
+                            .map(|rank| rank.to_string())
+                            .unwrap_or_default(),
+                    ]),
+                    None => row.extend(std::iter::repeat_n(String::new(), 4)),
+                }
+            }
+            writeln!(w, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// Set when each time was first observed, carrying it over from the
+    /// previous results if the time is unchanged and using `now` otherwise.
+    pub fn stamp_observed_at(&mut self, prev: Option<&RallyResults>, now: i64) {
+        for driver in &mut self.driver_results {
+            for (stage, stage_result) in self.stages.iter().zip(&mut driver.stages) {
+                let Some(stage_result) = stage_result else {
+                    continue;
+                };
+                let prev_stage_result =
+                    prev.and_then(|prev| prev.stage_result(&driver.name, stage));
+                stage_result.observed_at = match prev_stage_result {
+                    Some(prev) if prev.time_ms == stage_result.time_ms => prev.observed_at,
+                    _ => Some(now),
+                };
+            }
+        }
+    }
+
+    /// The index of `stage` in these results, matched by [`stage_key`].
+    pub fn stage_index(&self, stage: &StageWithLeaderboard) -> Option<usize> {
+        let key = stage_key(stage);
+        self.stages.iter().position(|stage| stage_key(stage) == key)
+    }
+
+    /// A driver's result on `stage`, matched by [`stage_key`].
+    pub fn stage_result(&self, name: &str, stage: &StageWithLeaderboard) -> Option<&StageResult> {
+        let stage_idx = self.stage_index(stage)?;
+        self.driver_results
+            .iter()
+            .find(|driver| driver.name == name)?
+            .stages
+            .get(stage_idx)?
+            .as_ref()
+    }
 }
 
-pub fn get_default_rallys() -> Vec<Rally> {
+/// Quote a CSV cell if it needs it.
+fn csv_escape(cell: &str) -> String {
+    if cell.contains([',', '"', '\n']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+/// The rallys we track, optionally with their reverse stages too.
+///
+/// Each stage is tracked once per weather in `weathers`, or only in dry
+/// weather if it's empty. Stages in different weathers are different stages
+/// with their own leaderboards, names and pages.
+pub fn get_default_rallys(include_reverse: bool, weathers: &[Weather]) -> Vec<Rally> {
+    let directions: &[Direction] = if include_reverse {
+        &[Direction::Forward, Direction::Reverse]
+    } else {
+        &[Direction::Forward]
+    };
+    let weathers: &[Weather] = if weathers.is_empty() {
+        &[Weather::Dry]
+    } else {
+        weathers
+    };
+    let stages = |area: Area, group: Group| -> Vec<StageWithLeaderboard> {
+        directions
+            .iter()
+            .cartesian_product(weathers)
+            .cartesian_product(stage_numbers(area))
+            .map(|((&direction, &weather), stage_number)| {
+                (
+                    Stage {
+                        area,
+                        stage_number: stage_number as _,
+                        direction,
+                    },
+                    group,
+                    weather,
+                )
+            })
+            .collect()
+    };
     vec![
         Rally {
             title: "kenya - group b".to_string(),
-            stages: [1, 2, 3, 4, 5, 6]
-                .map(|stage_number| {
-                    (
-                        Stage {
-                            area: Area::Kenya,
-                            stage_number,
-                            direction: Direction::Forward,
-                        },
-                        Group::GroupB,
-                        Weather::Dry,
-                    )
-                })
-                .to_vec(),
+            stages: stages(Area::Kenya, Group::GroupB),
+            precision: Precision::default(),
         },
         Rally {
             title: "norway - group 4".to_string(),
-            stages: [1, 2, 3, 4, 5, 6]
-                .map(|stage_number| {
-                    (
-                        Stage {
-                            area: Area::Norway,
-                            stage_number,
-                            direction: Direction::Forward,
-                        },
-                        Group::Eighties,
-                        Weather::Dry,
-                    )
-                })
-                .to_vec(),
+            stages: stages(Area::Norway, Group::Eighties),
+            precision: Precision::default(),
         },
     ]
 }
 
-pub fn get_rally_results(
+/// A driver's result on a stage, from their entry on the stage leaderboard.
+///
+/// The entry's `score` is the time, in milliseconds unless `ms_per_score` says
+/// otherwise, and its `rank` is the rank on the leaderboard it came from, which
+/// is the local rank for friends leaderboards. World ranks outside of
+/// [`VALID_WORLD_RANKS`] are dropped.
+pub fn stage_result_from_entry(
+    entry: &LeaderboardEntry,
+    world_rank: Option<usize>,
+    ms_per_score: usize,
+) -> StageResult {
+    StageResult {
+        car: entry.car_id,
+        time_ms: entry.score * ms_per_score,
+        local_rank: entry.rank,
+        world_rank: world_rank.and_then(valid_world_rank),
+        world_entries: None,
+        observed_at: None,
+    }
+}
+
+/// A world rank, unless it's outside of [`VALID_WORLD_RANKS`].
+fn valid_world_rank(world_rank: usize) -> Option<usize> {
+    VALID_WORLD_RANKS
+        .contains(&world_rank)
+        .then_some(world_rank)
+}
+
+/// The friends leaderboard URL for each stage, containing all users.
+pub fn friends_urls(
     leaderboards: &[(StageWithLeaderboard, Platform)],
     user_ids: &[u64],
-    user_names: &[&str],
-) -> Result<RallyResults, Whatever> {
-    let stages = leaderboards
-        .iter()
-        .copied()
-        .map(|(stage, _)| stage)
-        .collect_vec();
-    let result_urls: Vec<_> = leaderboards
+) -> Result<Vec<String>, Whatever> {
+    let Some((user_id, friend_ids)) = user_ids.split_first() else {
+        whatever!("No users configured");
+    };
+    Ok(leaderboards
         .iter()
         .copied()
         .map(|((stage, group, weather), platform)| {
@@ -99,104 +403,704 @@ pub fn get_rally_results(
                 platform,
                 filter: Filter::Friends,
             })
-            .as_url(user_ids[0], &user_ids[1..])
+            .as_url(*user_id, friend_ids)
         })
-        .collect();
-    let leaderboard_results = http::download_all::<Response>(&result_urls);
+        .collect())
+}
+
+/// How many URLs [`get_rally_results`] downloads for some leaderboards.
+pub fn download_count(leaderboards: usize, users: usize) -> usize {
+    // One friends leaderboard and one world rank per user, for each stage
+    leaderboards * (1 + users)
+}
+
+/// How long [`download`] spent on what, for profiling.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DownloadTimes {
+    /// Downloading the friends leaderboards, with the times.
+    pub entries: Duration,
+    /// Downloading each user's world rank on each stage.
+    pub ranks: Duration,
+    /// Downloading the top of the global leaderboards.
+    pub world: Duration,
+    /// Working out whose times are whose.
+    pub assembly: Duration,
+}
+
+/// The (stage index, user index) pairs to ask for world ranks, given how many
+/// entries each stage's friends leaderboard has, or `None` if it couldn't be
+/// downloaded.
+///
+/// The entries don't say whose times they are, so usually everyone is asked.
+/// But times are never removed from a leaderboard, so a stage with as many
+/// entries as there were users with a time on it in `prev` has the same users
+/// on it, and only they are asked. Nobody is asked about a stage without
+/// entries.
+fn rank_requests(
+    stages: &[StageWithLeaderboard],
+    entries: &[Option<usize>],
+    user_names: &[&str],
+    prev: Option<&RallyResults>,
+) -> Vec<(usize, usize)> {
+    let mut requests = Vec::new();
+    for (stage_idx, (stage, entries)) in stages.iter().zip(entries).enumerate() {
+        let Some(entries) = *entries else {
+            continue;
+        };
+        if entries == 0 {
+            continue;
+        }
+        let known = user_names
+            .iter()
+            .positions(|name| prev.is_some_and(|prev| prev.stage_result(name, stage).is_some()))
+            .collect_vec();
+        if known.len() == entries {
+            requests.extend(known.into_iter().map(|user_idx| (stage_idx, user_idx)));
+        } else {
+            requests.extend((0..user_names.len()).map(|user_idx| (stage_idx, user_idx)));
+        }
+    }
+    requests
+}
+
+/// Download the results of the users on some leaderboards, adding how long it
+/// took to `times`. `prev` is the previous run's results, if there are any,
+/// which saves asking for some world ranks, see [`rank_requests`].
+pub fn get_rally_results(
+    leaderboards: &[(StageWithLeaderboard, Platform)],
+    user_ids: &[u64],
+    user_names: &[&str],
+    prev: Option<&RallyResults>,
+    ms_per_score: usize,
+    progress: &ProgressBar,
+    times: &mut DownloadTimes,
+) -> Result<RallyResults, Whatever> {
+    let _span = tracing::info_span!(
+        "get_rally_results",
+        area = ?leaderboards.first().map(|((stage, _, _), _)| stage.area),
+        users = user_ids.len(),
+        stages = leaderboards.len(),
+        cache = http::cache_enabled(),
+    )
+    .entered();
+
+    let stages = leaderboards
+        .iter()
+        .copied()
+        .map(|(stage, _)| stage)
+        .collect_vec();
+    let result_urls = friends_urls(leaderboards, user_ids)?;
+    tracing::debug!(urls = result_urls.len(), "downloading friends leaderboards");
+    let start = Instant::now();
+    let leaderboard_results = http::download_all::<Response>(&result_urls, progress);
+    times.entries += start.elapsed();
 
-    // TODO: only ask for rank of users who have a time
-    let rank_urls: Vec<_> = user_ids
+    let entries = leaderboard_results
         .iter()
-        .cartesian_product(leaderboards.iter().copied().map(
-            |((stage, group, weather), platform)| Leaderboard {
+        .map(|leaderboard| {
+            leaderboard
+                .as_ref()
+                .ok()
+                .map(|response| response.leaderboard.len())
+        })
+        .collect_vec();
+    let rank_requests = rank_requests(&stages, &entries, user_names, prev);
+    progress.inc((leaderboards.len() * user_ids.len() - rank_requests.len()) as _);
+    let rank_urls = rank_requests
+        .iter()
+        .map(|&(stage_idx, user_idx)| {
+            let ((stage, group, weather), platform) = leaderboards[stage_idx];
+            Leaderboard {
                 stage,
                 weather,
                 group,
                 platform,
                 filter: Filter::PlayerRank,
-            },
-        ))
-        .map(|(user, leaderboard)| leaderboard.as_url(*user, &[]))
-        .collect();
+            }
+            .as_url(user_ids[user_idx], &[])
+        })
+        .collect_vec();
 
     #[derive(Serialize, Deserialize, Clone, Debug)]
     struct Rank {
         #[serde(rename = "result")]
         _result: i32,
         rank: usize,
+        /// How many entries the leaderboard has. Not every response says.
+        #[serde(default)]
+        total: Option<usize>,
     }
 
-    // World rank, in the same order we asked for (so users x leaderboard: [(user1, board1), (user1, board2), ..., (user2, board1), ...])
-    let ranks = http::download_all::<Rank>(&rank_urls);
-    assert_eq!(ranks.len(), user_ids.len() * leaderboards.len());
-    // If we chunk by number of leaderboards we get chunks per user.
-    let world_rank_by_user: Vec<_> = ranks.chunks_exact(leaderboards.len()).collect();
+    tracing::debug!(urls = rank_urls.len(), "downloading world ranks");
+    let start = Instant::now();
+    let ranks = http::download_all::<Rank>(&rank_urls, progress);
+    times.ranks += start.elapsed();
+    let start = Instant::now();
+    // { (stage index, user index) => (world rank, leaderboard entries) }
+    let ranks: BTreeMap<(usize, usize), (usize, Option<usize>)> = rank_requests
+        .into_iter()
+        .zip(ranks)
+        .filter_map(|(request, rank)| {
+            Some((request, rank.ok().map(|rank| (rank.rank, rank.total))?))
+        })
+        .collect();
 
-    let mut driver_results: BTreeMap<String, Vec<Option<StageResult>>> = BTreeMap::new();
-    for (stage_idx, leaderboard) in leaderboard_results.into_iter().enumerate() {
-        let mut entries = leaderboard.unwrap().leaderboard;
+    // Each user's world rank on each stage, `None` where we didn't ask and for
+    // users without a rank
+    let world_ranks = (0..user_ids.len())
+        .map(|user_idx| {
+            (0..stages.len())
+                .map(|stage_idx| ranks.get(&(stage_idx, user_idx)).copied())
+                .collect_vec()
+        })
+        .collect_vec();
+    let boards = leaderboard_results
+        .into_iter()
+        .enumerate()
+        .map(|(stage_idx, leaderboard)| match leaderboard {
+            Ok(response) => Some(
+                response
+                    .leaderboard
+                    .iter()
+                    .map(|entry| stage_result_from_entry(entry, None, ms_per_score))
+                    .collect(),
+            ),
+            Err(e) => {
+                let (stage, _group, weather) = stages[stage_idx];
+                tracing::warn!(
+                    "couldn't download {} ({weather}), skipping it\n{e}",
+                    StageName(&stage)
+                );
+                None
+            }
+        })
+        .collect_vec();
+    let driver_results = match_drivers(&stages, user_names, boards, &world_ranks);
+    let results = RallyResults::from_driver_results(stages, driver_results);
+    times.assembly += start.elapsed();
 
-        // We don't know which user id is which user! But we know the relative
-        // ranking of usernames (LeaderboardEntry), and the world rank for each
-        // user (world_rank_by_user). Sort the entries by local rank, and lookup
-        // the name (and world rank) from the corresponding (ranking-relative)
-        // world rank.
+    Ok(results)
+}
 
-        entries.sort_by_key(|entry| entry.rank);
+/// Work out whose times are whose on each stage.
+///
+/// `boards` has the times on each stage's friends leaderboard, or `None` for
+/// stages that failed to download, and `world_ranks` has each user's world
+/// rank on each stage and how many entries the world leaderboard has, in the
+/// same order as `user_names`.
+///
+/// We don't know which user id is which user! But we know the relative
+/// ranking of the times (the local rank), and the world rank for each user.
+/// Sorting both gives the name (and world rank) of each time. Users whose
+/// world rank is missing can only be guessed, see the comment below.
+fn match_drivers(
+    stages: &[StageWithLeaderboard],
+    user_names: &[&str],
+    boards: Vec<Option<Vec<StageResult>>>,
+    world_ranks: &[Vec<Option<(usize, Option<usize>)>>],
+) -> BTreeMap<String, Vec<Option<StageResult>>> {
+    let mut driver_results: BTreeMap<String, Vec<Option<StageResult>>> = BTreeMap::new();
+    for (stage_idx, board) in boards.into_iter().enumerate() {
+        // A stage that failed to download has no results, the rest of the
+        // rally is still worth showing.
+        let Some(mut board) = board else {
+            continue;
+        };
+        board.sort_by_key(|stage_result| stage_result.local_rank);
 
-        let mut sorted_world_ranks = world_rank_by_user
+        let mut sorted_world_ranks = world_ranks
             .iter()
-            .map(|user_ranks| user_ranks.get(stage_idx).unwrap())
             .zip(user_names)
-            .filter_map(|(r, name)| r.as_ref().map(|r| (r.rank, name)))
-            .sorted_by_key(|(rank, _name)| *rank);
+            // Users without a time have no rank to download
+            .filter_map(|(user_ranks, name)| {
+                let (rank, total) = user_ranks.get(stage_idx).copied().flatten()?;
+                Some((Some(rank), total, name))
+            })
+            .sorted_by_key(|(rank, _total, _name)| *rank)
+            .collect_vec();
 
-        for entry in entries {
-            let (world_rank, name) = sorted_world_ranks.next().unwrap();
-            let entry_for_user = driver_results
+        // If world ranks failed to download we can't tell whose times are
+        // whose. Rather than dropping the times, guess that the users without
+        // a world rank are the slowest ones, in the order they're configured.
+        if sorted_world_ranks.len() < board.len() {
+            let (stage, _group, weather) = stages[stage_idx];
+            tracing::warn!(
+                "missing world ranks for {} ({weather}), guessing whose {} times \
+                 are whose by local rank",
+                StageName(&stage),
+                board.len()
+            );
+            sorted_world_ranks.extend(
+                world_ranks
+                    .iter()
+                    .zip(user_names)
+                    .filter(|(user_ranks, _name)| {
+                        user_ranks.get(stage_idx).copied().flatten().is_none()
+                    })
+                    .map(|(_user_ranks, name)| (None, None, name)),
+            );
+        }
+
+        for (stage_result, (world_rank, world_entries, name)) in
+            board.into_iter().zip(sorted_world_ranks)
+        {
+            // The raw ranks are still used for matching names since sentinels
+            // sort consistently, but they aren't reported as world ranks.
+            let stage_result = StageResult {
+                world_rank: world_rank.and_then(valid_world_rank),
+                world_entries,
+                ..stage_result
+            };
+            if !PLAUSIBLE_STAGE_TIMES_MS.contains(&stage_result.time_ms) {
+                let (stage, _group, weather) = stages[stage_idx];
+                tracing::warn!(
+                    "{name} has an implausible time of {} ms on {} ({weather}), \
+                     maybe `ms_per_score` needs changing",
+                    stage_result.time_ms,
+                    StageName(&stage),
+                );
+            }
+            driver_results
                 .entry(name.to_string())
-                .or_insert_with(|| vec![Option::None; leaderboards.len()]);
-            entry_for_user[stage_idx] = Some(StageResult {
-                car: entry.car_id,
-                time_ms: entry.score,
-                local_rank: entry.rank,
-                world_rank: Some(world_rank),
+                .or_insert_with(|| vec![None; stages.len()])[stage_idx] = Some(stage_result);
+        }
+    }
+    driver_results
+}
+
+/// The top of the global leaderboard on each stage as (name, result), fastest
+/// first.
+pub type WorldResults = Vec<Vec<(String, StageResult)>>;
+
+/// The `top` times on the global leaderboard of each stage.
+///
+/// The global leaderboard doesn't say whose times are whose, so names are
+/// resolved by world rank instead: an entry with the same rank as one of our
+/// drivers' world rank on the stage in `friends` is theirs, and everyone else
+/// is named after their rank, like `#12`. The entry's rank is the world rank,
+/// so it's used as both the local and the world rank.
+pub fn get_world_results(
+    leaderboards: &[(StageWithLeaderboard, Platform)],
+    friends: &RallyResults,
+    user_id: u64,
+    top: usize,
+    ms_per_score: usize,
+    progress: &ProgressBar,
+) -> Result<WorldResults, Whatever> {
+    let urls = leaderboards
+        .iter()
+        .copied()
+        .map(|((stage, group, weather), platform)| {
+            (Leaderboard {
+                stage,
+                weather,
+                group,
+                platform,
+                filter: Filter::All,
+            })
+            .as_url(user_id, &[])
+        })
+        .collect_vec();
+    tracing::debug!(urls = urls.len(), "downloading global leaderboards");
+    http::download_all::<Response>(&urls, progress)
+        .into_iter()
+        .enumerate()
+        .map(|(stage_idx, leaderboard)| {
+            let entries = leaderboard
+                .with_whatever_context(|e| format!("Couldn't download a leaderboard\n{e}"))?
+                .leaderboard;
+            Ok(entries
+                .iter()
+                .sorted_by_key(|entry| entry.rank)
+                .take(top)
+                .map(|entry| {
+                    let name = friends
+                        .stage_results
+                        .get(stage_idx)
+                        .and_then(|stage_results| {
+                            stage_results
+                                .iter()
+                                .find(|(_, r)| r.world_rank == Some(entry.rank))
+                        })
+                        .map_or_else(|| format!("#{}", entry.rank), |(name, _)| name.clone());
+                    (
+                        name,
+                        stage_result_from_entry(entry, Some(entry.rank), ms_per_score),
+                    )
+                })
+                .collect())
+        })
+        .collect()
+}
+
+/// Download the results of all rallys, and the fastest times in the world on
+/// their stages if `world_top` is set, with how long it took.
+pub fn download(
+    rallys: Vec<Rally>,
+    platform: Platform,
+    user_ids: Vec<u64>,
+    user_names: Vec<&str>,
+    prev: Option<&Db>,
+    ms_per_score: usize,
+    world_top: Option<usize>,
+) -> Result<(Db, BTreeMap<String, WorldResults>, DownloadTimes), Whatever> {
+    // One progress bar for all rallys
+    let progress = http::progress_bar(
+        rallys
+            .iter()
+            .map(|rally| {
+                let world = if world_top.is_some() {
+                    rally.stages.len()
+                } else {
+                    0
+                };
+                download_count(rally.stages.len(), user_ids.len()) + world
             })
+            .sum(),
+    );
+    let mut results = Vec::new();
+    let mut world = BTreeMap::new();
+    let mut times = DownloadTimes::default();
+    for rally in &rallys {
+        let leaderboards = rally
+            .stages
+            .iter()
+            .copied()
+            .map(|stage| (stage, platform))
+            .collect_vec();
+        results.push(get_rally_results(
+            &leaderboards,
+            &user_ids,
+            &user_names,
+            prev.and_then(|prev| prev.rally_results(&rally.title)),
+            ms_per_score,
+            &progress,
+            &mut times,
+        )?);
+        if let Some(top) = world_top {
+            let start = Instant::now();
+            let friends = results.last().expect("just pushed");
+            world.insert(
+                rally.title.clone(),
+                get_world_results(
+                    &leaderboards,
+                    friends,
+                    user_ids[0],
+                    top,
+                    ms_per_score,
+                    &progress,
+                )?,
+            );
+            times.world += start.elapsed();
         }
     }
+    progress.finish();
+
+    let db = Db {
+        version: DB_VERSION,
+        rallys,
+        results,
+        platform,
+        user_ids,
+        user_names: user_names.into_iter().map(str::to_string).collect(),
+    };
+    Ok((db, world, times))
+}
+
+/// The newest snapshot in `data/` if it's at most `max_age` old, otherwise a
+/// fresh download of the configured rallys. Downloads aren't saved as
+/// snapshots, that's up to the caller.
+pub fn load_or_download(config: &Config, max_age: Duration) -> Result<Db, Whatever> {
+    let prev = match history::latest_snapshot_at("data")? {
+        Some((ts, db)) if chrono::Utc::now().timestamp() - ts <= max_age.as_secs() as i64 => {
+            return Ok(db);
+        }
+        latest => latest.map(|(_, db)| db),
+    };
+    let (user_ids, user_names) = config.users();
+    let (db, _world, _times) = download(
+        config.rallys()?,
+        config.platform,
+        user_ids,
+        user_names,
+        prev.as_ref(),
+        config.ms_per_score,
+        None,
+    )?;
+    Ok(db)
+}
+
+/// How many stages are driven in each weather, over all rallys.
+pub fn weather_distribution(rallys: &[Rally]) -> BTreeMap<Weather, usize> {
+    let mut distribution = BTreeMap::new();
+    for (_, _, weather) in rallys.iter().flat_map(|rally| &rally.stages) {
+        *distribution.entry(*weather).or_default() += 1;
+    }
+    distribution
+}
+
+/// The stages a driver doesn't have a time on yet.
+pub fn remaining_stages(
+    driver: &DriverResult,
+    stages: &[StageWithLeaderboard],
+) -> Vec<StageWithLeaderboard> {
+    stages
+        .iter()
+        .zip(&driver.stages)
+        .filter(|(_, stage_result)| stage_result.is_none())
+        .map(|(stage, _)| *stage)
+        .collect()
+}
+
+/// Indices of the stages where no driver has a time.
+pub fn unattempted_stages(results: &RallyResults) -> Vec<usize> {
+    (0..results.stages.len())
+        .filter(|&i| {
+            results
+                .driver_results
+                .iter()
+                .all(|driver| driver.stages[i].is_none())
+        })
+        .collect()
+}
+
+/// How to order full times with the same total time.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TieBreak {
+    /// By user name.
+    #[default]
+    Alphabetical,
+    /// By the sum of local ranks on each stage, lowest first. Falls back to
+    /// user name.
+    LocalRanks,
+}
+
+/// A [`Platform`] that parses from and displays as its name, like `steam`.
+/// Parsing ignores case.
+#[derive(Clone, Copy)]
+pub struct PlatformName(pub Platform);
+
+impl FromStr for PlatformName {
+    type Err = Whatever;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |name: &str| {
+            Platform::deserialize(name.into_deserializer()).map_err(|e: serde::de::value::Error| e)
+        };
+        let mut capitalized = s.to_lowercase();
+        if let Some(first) = capitalized.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        if let Some(platform) = [s.to_lowercase(), capitalized, s.to_uppercase()]
+            .iter()
+            .find_map(|name| parse(name).ok())
+        {
+            return Ok(PlatformName(platform));
+        }
+        // The error lists the valid platforms
+        parse(s)
+            .map(PlatformName)
+            .with_whatever_context(|e| format!("Unknown platform {s:?}\n{e}"))
+    }
+}
+
+impl fmt::Display for PlatformName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self.0) {
+            Ok(serde_json::Value::String(name)) => write!(f, "{}", name.to_lowercase()),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// The share of the other finishers that a time is faster than, in percent.
+///
+/// A single finisher is in the 100th percentile.
+pub fn percentile(time_ms: usize, all_times: &[usize]) -> f32 {
+    if all_times.len() <= 1 {
+        return 100.0;
+    }
+    let slower = all_times.iter().filter(|&&time| time > time_ms).count();
+    slower as f32 * 100.0 / (all_times.len() - 1) as f32
+}
+
+/// The share of the world a world rank is in the top of, in percent. Rank 1
+/// of 200 is the top 0.5%.
+pub fn world_top_percent(world_rank: usize, world_entries: usize) -> f32 {
+    world_rank as f32 * 100.0 / world_entries.max(1) as f32
+}
+
+/// Two drivers' times on one stage, see [`head_to_head`].
+#[derive(Clone, Copy, Debug)]
+pub struct HeadToHeadStage {
+    pub a_ms: usize,
+    pub b_ms: usize,
+    /// How far `a` is behind `b`, negative if `a` is ahead.
+    pub delta_ms: i64,
+}
+
+#[derive(Debug)]
+pub struct HeadToHead {
+    /// `None` for stages where at least one of the drivers doesn't have a
+    /// time, so they can't be compared.
+    pub stages: Vec<Option<HeadToHeadStage>>,
+    /// How many stages each driver was faster on, `a` first.
+    pub wins: [usize; 2],
+}
+
+/// Compare two drivers stage by stage.
+pub fn head_to_head(results: &RallyResults, a: &str, b: &str) -> HeadToHead {
+    let stages_of = |name: &str| {
+        results
+            .driver_results
+            .iter()
+            .find(|driver| driver.name == name)
+            .map(|driver| driver.stages.as_slice())
+            .unwrap_or_default()
+    };
+    let (a_stages, b_stages) = (stages_of(a), stages_of(b));
+    let stages = (0..results.stages.len())
+        .map(|i| {
+            let a_ms = a_stages.get(i)?.as_ref()?.time_ms;
+            let b_ms = b_stages.get(i)?.as_ref()?.time_ms;
+            Some(HeadToHeadStage {
+                a_ms,
+                b_ms,
+                delta_ms: a_ms as i64 - b_ms as i64,
+            })
+        })
+        .collect_vec();
+    let wins = [
+        stages.iter().flatten().filter(|s| s.a_ms < s.b_ms).count(),
+        stages.iter().flatten().filter(|s| s.b_ms < s.a_ms).count(),
+    ];
+    HeadToHead { stages, wins }
+}
+
+/// How many stages a driver was fastest on, and in the top three on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageWins {
+    pub wins: usize,
+    pub podiums: usize,
+}
 
-    let mut stage_results = stages.iter().map(|_| Vec::new()).collect_vec();
-    for (driver, driver_stage_results) in &driver_results {
-        for (i, driver_stage_result) in driver_stage_results.iter().enumerate() {
-            let Some(driver_stage_result) = driver_stage_result else {
+/// Stage wins and podiums for each driver with at least one podium, most wins
+/// first. Everyone tied for a place gets it, so a stage can have several
+/// winners.
+pub fn stage_wins(results: &RallyResults) -> Vec<(String, StageWins)> {
+    let mut stage_wins: BTreeMap<&str, StageWins> = BTreeMap::new();
+    for stage_results in &results.stage_results {
+        for (name, stage_result) in stage_results {
+            let faster = stage_results
+                .iter()
+                .filter(|(_, other)| other.time_ms < stage_result.time_ms)
+                .count();
+            if faster >= 3 {
                 continue;
-            };
-            stage_results[i].push((driver.clone(), driver_stage_result.clone()));
+            }
+            let driver = stage_wins.entry(name).or_default();
+            driver.podiums += 1;
+            if faster == 0 {
+                driver.wins += 1;
+            }
         }
     }
-    for stage_result in &mut stage_results {
-        stage_result.sort_by_key(|(_, x)| x.time_ms);
+    stage_wins
+        .into_iter()
+        .map(|(name, wins)| (name.to_string(), wins))
+        .sorted_by_key(|(_, wins)| std::cmp::Reverse((wins.wins, wins.podiums)))
+        .collect()
+}
+
+/// How a driver did with one car, see [`car_stats`].
+#[derive(Clone, Debug)]
+pub struct CarStats {
+    /// The indices of the stages driven with the car.
+    pub stages: Vec<usize>,
+    /// The average interval to the fastest time on those stages.
+    pub average_delta_ms: usize,
+}
+
+/// The cars each driver used, by name, and how they did with them.
+///
+/// Car ids mean different cars in different groups, so each stage's car is
+/// named with the group of that stage.
+pub fn car_stats(results: &RallyResults) -> BTreeMap<String, BTreeMap<String, CarStats>> {
+    // { driver => { car => (stages, summed delta) } }
+    let mut cars: BTreeMap<String, BTreeMap<String, (Vec<usize>, usize)>> = BTreeMap::new();
+    for (stage_idx, ((_stage, group, _weather), stage_results)) in results
+        .stages
+        .iter()
+        .zip(&results.stage_results)
+        .enumerate()
+    {
+        let Some(fastest) = stage_results.iter().map(|(_, r)| r.time_ms).min() else {
+            continue;
+        };
+        for (name, stage_result) in stage_results {
+            let car = cars
+                .entry(name.clone())
+                .or_default()
+                .entry(car_name(*group, stage_result.car).to_string())
+                .or_default();
+            car.0.push(stage_idx);
+            car.1 += stage_result.time_ms - fastest;
+        }
     }
+    cars.into_iter()
+        .map(|(name, cars)| {
+            let cars = cars
+                .into_iter()
+                .map(|(car, (stages, delta_ms))| {
+                    let average_delta_ms = delta_ms / stages.len();
+                    (
+                        car,
+                        CarStats {
+                            stages,
+                            average_delta_ms,
+                        },
+                    )
+                })
+                .collect();
+            (name, cars)
+        })
+        .collect()
+}
 
-    Ok(RallyResults {
-        stages,
-        driver_results: driver_results
-            .into_iter()
-            .map(|(name, stages)| DriverResult { name, stages })
-            .collect(),
-        stage_results,
-    })
+/// The driver with the most last places, counting only stages with at least
+/// two finishers.
+pub fn bogey_driver(results: &RallyResults) -> Option<String> {
+    let mut last_places: BTreeMap<&str, usize> = BTreeMap::new();
+    for stage_results in &results.stage_results {
+        if stage_results.len() < 2 {
+            continue;
+        }
+        let (name, _) = stage_results.last().unwrap();
+        *last_places.entry(name).or_default() += 1;
+    }
+    // On ties, the first name alphabetically gets the honor
+    last_places
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(name, _)| name.to_string())
 }
 
 #[derive(Debug)]
 pub struct FullTime<'s> {
     pub total_time: usize,
+    /// Rank by total time among the full times, starting at 1. See
+    /// [`split_times`] for ties.
     pub total_local_rank: usize,
     pub user_name: &'s str,
     pub stage_times: Vec<usize>,
     pub local_rank: Vec<usize>,
     pub world_rank: Vec<Option<usize>>,
     pub cars: Vec<usize>,
+    /// The standard deviation of the intervals to the fastest time on each
+    /// stage, in milliseconds. Lower is more consistent.
+    pub interval_std_dev_ms: f32,
 }
 
 #[derive(Debug)]
@@ -210,9 +1114,25 @@ pub struct PartialTime<'s> {
     pub cars: Vec<Option<usize>>,
 }
 
-pub fn split_times(rally: &RallyResults) -> (Vec<FullTime<'_>>, Vec<PartialTime<'_>>) {
+/// Split the drivers into those who have finished every stage, those who have
+/// finished some and those who haven't finished any (by name).
+///
+/// Full times are sorted by total time, then `tie_break`, then name, and
+/// ranked by the first two so drivers who are still tied share a rank.
+/// Partial times are sorted by finished stages, most first, then total time,
+/// then name, and aren't ranked. Drivers without times are sorted by name.
+pub fn split_times(
+    rally: &RallyResults,
+    tie_break: TieBreak,
+) -> (Vec<FullTime<'_>>, Vec<PartialTime<'_>>, Vec<&str>) {
     let mut full_times = Vec::new();
     let mut partial_times = Vec::new();
+    let mut none_times = Vec::new();
+    let fastest_stages = rally
+        .stage_results
+        .iter()
+        .map(|stage_results| stage_results.iter().map(|(_, r)| r.time_ms).min())
+        .collect_vec();
 
     for driver in rally.driver_results.iter() {
         let times = driver
@@ -232,9 +1152,14 @@ pub fn split_times(rally: &RallyResults) -> (Vec<FullTime<'_>>, Vec<PartialTime<
             .iter()
             .map(|o| o.as_ref().map(|stage| stage.world_rank));
         let total_time: usize = times.clone().flatten().sum();
-        let finished = driver.stages.iter().filter(|o| o.is_some()).count();
-        let is_full = finished == driver.stages.len();
+        let (finished, total) = driver.completion(driver.stages.len());
+        let is_full = finished == total;
         if is_full {
+            let intervals = times
+                .clone()
+                .zip(&fastest_stages)
+                .map(|(time, fastest)| (time.unwrap() - fastest.unwrap()) as f32)
+                .collect_vec();
             full_times.push(FullTime {
                 total_time,
                 total_local_rank: 0, // Need to load all full times before we can set the actual value
@@ -243,7 +1168,10 @@ pub fn split_times(rally: &RallyResults) -> (Vec<FullTime<'_>>, Vec<PartialTime<
                 local_rank: local_rank.map(|o| o.unwrap()).collect(),
                 world_rank: world_rank.map(|o| o.unwrap()).collect(),
                 cars: cars.map(|o| o.unwrap()).collect(),
+                interval_std_dev_ms: std_dev(&intervals),
             })
+        } else if finished == 0 {
+            none_times.push(driver.name.as_str());
         } else {
             partial_times.push(PartialTime {
                 finished_stages: finished,
@@ -256,21 +1184,60 @@ pub fn split_times(rally: &RallyResults) -> (Vec<FullTime<'_>>, Vec<PartialTime<
             });
         }
     }
-    full_times.sort_by_key(|ft| ft.total_time);
-    // All times are sorted, so now we can set the total local rank
-    full_times
-        .iter_mut()
-        .enumerate()
-        .for_each(|(i, ft)| ft.total_local_rank = i + 1);
-    // sort partialtimes first by amount of finished stages (largest first), then by total time (smallest first)
+    let standing = |ft1: &FullTime, ft2: &FullTime| {
+        ft1.total_time
+            .cmp(&ft2.total_time)
+            .then_with(|| match tie_break {
+                TieBreak::Alphabetical => Ordering::Equal,
+                TieBreak::LocalRanks => ft1
+                    .local_rank
+                    .iter()
+                    .sum::<usize>()
+                    .cmp(&ft2.local_rank.iter().sum()),
+            })
+    };
+    full_times.sort_by(|ft1, ft2| standing(ft1, ft2).then(ft1.user_name.cmp(ft2.user_name)));
+    // All times are sorted, so now we can set the total local rank. Drivers
+    // who are still tied share a rank, and the next rank is skipped.
+    for i in 0..full_times.len() {
+        full_times[i].total_local_rank =
+            if i > 0 && standing(&full_times[i - 1], &full_times[i]) == Ordering::Equal {
+                full_times[i - 1].total_local_rank
+            } else {
+                i + 1
+            };
+    }
+    // sort partialtimes first by amount of finished stages (largest first), then by total time (smallest first), then by name
     partial_times.sort_by(|pt1, pt2| {
         pt2.finished_stages
             .cmp(&pt1.finished_stages)
             .then(pt1.total_time.cmp(&pt2.total_time))
+            .then(pt1.user_name.cmp(pt2.user_name))
     });
-    (full_times, partial_times)
+    none_times.sort();
+    (full_times, partial_times, none_times)
+}
+
+/// The population standard deviation, 0 for no values.
+fn std_dev(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    (values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f32>()
+        / values.len() as f32)
+        .sqrt()
 }
 
+/// The fastest total time and the fastest time on each stage.
+///
+/// The total is the fastest among full times only, since partial totals
+/// aren't comparable to it (see [`fastest_partial_totals`] for those). So it's
+/// `None` exactly when there are no full times, even if the stages have times.
+/// A stage is `None` only if nobody has driven it.
 pub fn fastest_times(
     full_times: &[FullTime],
     rally: &RallyResults,
@@ -294,3 +1261,927 @@ pub fn fastest_times(
     }
     (fastest_total, fastest_per_stage)
 }
+
+/// The fastest total time among partial times, per number of finished stages.
+///
+/// Partial totals are only comparable between drivers who have finished the
+/// same number of stages, so that's what partial intervals are relative to.
+pub fn fastest_partial_totals(partial_times: &[PartialTime]) -> BTreeMap<usize, usize> {
+    let mut fastest = BTreeMap::new();
+    for pt in partial_times {
+        fastest
+            .entry(pt.finished_stages)
+            .and_modify(|fastest: &mut usize| *fastest = (*fastest).min(pt.total_time))
+            .or_insert(pt.total_time);
+    }
+    fastest
+}
+
+/// How much each part of the driver of the rally score counts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DriverOfTheRallyWeights {
+    pub total_rank: f32,
+    pub stage_wins: f32,
+    pub consistency: f32,
+}
+
+impl Default for DriverOfTheRallyWeights {
+    fn default() -> Self {
+        Self {
+            total_rank: 1.0,
+            stage_wins: 1.0,
+            consistency: 1.0,
+        }
+    }
+}
+
+/// The best driver of a rally, and why.
+///
+/// Every part of the score is between 0 and 1, higher is better.
+#[derive(Debug)]
+pub struct DriverOfTheRally<'s> {
+    pub user_name: &'s str,
+    pub score: f32,
+    /// 1 for the fastest total time, 0 for the slowest.
+    pub total_rank: f32,
+    /// Share of stages with the fastest time.
+    pub stage_wins: f32,
+    /// Lower spread of the per-stage gap to the fastest time (in percent) is
+    /// more consistent.
+    pub consistency: f32,
+}
+
+/// Crown a driver of the rally among the full times.
+///
+/// Returns `None` if there are fewer than two full times, since there's no
+/// competition then.
+pub fn driver_of_the_rally<'s>(
+    full_times: &[FullTime<'s>],
+    fastest_stages: &[Option<usize>],
+    weights: DriverOfTheRallyWeights,
+) -> Option<DriverOfTheRally<'s>> {
+    if full_times.len() < 2 {
+        return None;
+    }
+    let last_rank = full_times.len() as f32 - 1.0;
+    full_times
+        .iter()
+        .map(|ft| {
+            let gaps = ft
+                .stage_times
+                .iter()
+                .zip(fastest_stages)
+                .map(|(&time, fastest)| {
+                    let fastest = fastest.unwrap();
+                    // A broken 0 ms time would divide by zero, like in `pace_bucket`
+                    (time - fastest) as f32 * 100.0 / fastest.max(1) as f32
+                })
+                .collect_vec();
+            let gap_stddev = std_dev(&gaps);
+            let wins = gaps.iter().filter(|&&gap| gap == 0.0).count();
+
+            let total_rank = (full_times.len() - ft.total_local_rank) as f32 / last_rank;
+            let stage_wins = wins as f32 / gaps.len() as f32;
+            let consistency = 1.0 / (1.0 + gap_stddev);
+            DriverOfTheRally {
+                user_name: ft.user_name,
+                score: weights.total_rank * total_rank
+                    + weights.stage_wins * stage_wins
+                    + weights.consistency * consistency,
+                total_rank,
+                stage_wins,
+                consistency,
+            }
+        })
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+/// A driver's row in the standings, see [`RallyResults::to_standings_table`].
+#[derive(Debug, Serialize)]
+pub struct StandingRow {
+    pub name: String,
+    /// Rank by total time. Only full times are ranked.
+    pub rank: Option<usize>,
+    pub total_ms: usize,
+    /// Interval to the fastest total time, or for partial times to the
+    /// fastest partial total time with as many finished stages.
+    pub total_delta_ms: usize,
+    pub is_partial: bool,
+    pub finished_stages: usize,
+    pub stages: Vec<Option<StandingStage>>,
+}
+
+/// A driver's result on a stage in the standings.
+#[derive(Debug, Serialize)]
+pub struct StandingStage {
+    pub time_ms: usize,
+    /// Interval to the fastest time on the stage.
+    pub delta_ms: usize,
+    pub car: usize,
+    pub local_rank: usize,
+    pub world_rank: Option<usize>,
+}
+
+impl RallyResults {
+    /// The standings as plain data, in the same order as the rally tables:
+    /// full times by total time, then partial times.
+    pub fn to_standings_table(&self, tie_break: TieBreak) -> Vec<StandingRow> {
+        let (full_times, partial_times, _) = split_times(self, tie_break);
+        let (fastest_total, fastest_stages) = fastest_times(&full_times, self);
+        let fastest_partials = fastest_partial_totals(&partial_times);
+
+        let stages = |name: &str| {
+            let driver = self
+                .driver_results
+                .iter()
+                .find(|driver| driver.name == name)
+                .unwrap();
+            driver
+                .stages
+                .iter()
+                .zip(&fastest_stages)
+                .map(|(stage_result, fastest)| {
+                    stage_result.as_ref().map(|stage_result| StandingStage {
+                        time_ms: stage_result.time_ms,
+                        delta_ms: stage_result.time_ms - fastest.unwrap(),
+                        car: stage_result.car,
+                        local_rank: stage_result.local_rank,
+                        world_rank: stage_result.world_rank,
+                    })
+                })
+                .collect_vec()
+        };
+
+        full_times
+            .iter()
+            .map(|ft| StandingRow {
+                name: ft.user_name.to_string(),
+                rank: Some(ft.total_local_rank),
+                total_ms: ft.total_time,
+                total_delta_ms: ft.total_time - fastest_total.expect("there are full times"),
+                is_partial: false,
+                finished_stages: ft.stage_times.len(),
+                stages: stages(ft.user_name),
+            })
+            .chain(partial_times.iter().map(|pt| StandingRow {
+                name: pt.user_name.to_string(),
+                rank: None,
+                total_ms: pt.total_time,
+                total_delta_ms: pt.total_time - fastest_partials[&pt.finished_stages],
+                is_partial: true,
+                finished_stages: pt.finished_stages,
+                stages: stages(pt.user_name),
+            }))
+            .collect()
+    }
+}
+
+/// A rough idea of how long a stage is, from how long it takes to drive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthClass {
+    Short,
+    Medium,
+    Long,
+    /// Too few times to tell.
+    Unknown,
+}
+
+impl LengthClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            LengthClass::Short => "short",
+            LengthClass::Medium => "medium",
+            LengthClass::Long => "long",
+            LengthClass::Unknown => "unknown",
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            LengthClass::Short => "▂",
+            LengthClass::Medium => "▄",
+            LengthClass::Long => "█",
+            LengthClass::Unknown => "?",
+        }
+    }
+}
+
+/// Classify a stage by the median time of its finishers. Needs at least three
+/// finishers.
+pub fn stage_length_class(results: &RallyResults, stage_idx: usize) -> LengthClass {
+    let times = results.stage_results[stage_idx]
+        .iter()
+        .map(|(_, r)| r.time_ms)
+        .sorted()
+        .collect_vec();
+    if times.len() < 3 {
+        return LengthClass::Unknown;
+    }
+    let median = times[times.len() / 2];
+    if median < 3 * 60 * 1000 {
+        LengthClass::Short
+    } else if median < 5 * 60 * 1000 {
+        LengthClass::Medium
+    } else {
+        LengthClass::Long
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{rally_results, stage, stage_result};
+
+    #[test]
+    fn match_drivers_skips_failed_leaderboards() {
+        let stages = [stage(1), stage(2)];
+        let boards = vec![
+            None,
+            Some(vec![stage_result(62_000, 2), stage_result(61_000, 1)]),
+        ];
+        let world_ranks = [
+            vec![None, Some((20, Some(100)))],
+            vec![None, Some((10, Some(100)))],
+        ];
+        let drivers = match_drivers(&stages, &["alice", "bob"], boards, &world_ranks);
+
+        assert!(drivers["alice"][0].is_none());
+        let alice = drivers["alice"][1].as_ref().unwrap();
+        assert_eq!(alice.time_ms, 62_000);
+        assert_eq!(alice.world_rank, Some(20));
+        assert_eq!(alice.world_entries, Some(100));
+        let bob = drivers["bob"][1].as_ref().unwrap();
+        assert_eq!(bob.time_ms, 61_000);
+        assert_eq!(bob.world_rank, Some(10));
+
+        let results = RallyResults::from_driver_results(stages.to_vec(), drivers);
+        let (full_times, partial_times, none_times) = split_times(&results, TieBreak::default());
+        assert!(full_times.is_empty());
+        assert_eq!(partial_times.len(), 2);
+        assert!(none_times.is_empty());
+    }
+
+    #[test]
+    fn match_drivers_keeps_times_without_world_ranks() {
+        let stages = [stage(1), stage(2)];
+        let boards = vec![
+            Some(vec![stage_result(61_000, 1), stage_result(62_000, 2)]),
+            Some(vec![stage_result(71_000, 1), stage_result(72_000, 2)]),
+        ];
+        // Stage 1's ranks downloaded, stage 2's failed for bob
+        let world_ranks = [
+            vec![Some((20, None)), Some((30, None))],
+            vec![Some((10, None)), None],
+        ];
+        let drivers = match_drivers(&stages, &["alice", "bob"], boards, &world_ranks);
+
+        assert_eq!(drivers["bob"][0].as_ref().unwrap().time_ms, 61_000);
+        assert_eq!(drivers["alice"][0].as_ref().unwrap().time_ms, 62_000);
+        let alice = drivers["alice"][1].as_ref().unwrap();
+        assert_eq!((alice.time_ms, alice.world_rank), (71_000, Some(30)));
+        let bob = drivers["bob"][1].as_ref().unwrap();
+        assert_eq!((bob.time_ms, bob.world_rank), (72_000, None));
+    }
+
+    #[test]
+    fn stamp_observed_at_matches_moved_stages() {
+        let prev = rally_results(&[("alice", &[Some(61_000), Some(62_000)])]);
+        let mut prev_moved = RallyResults::from_driver_results(
+            vec![stage(2), stage(1)],
+            BTreeMap::from([(
+                "alice".to_string(),
+                vec![Some(stage_result(62_000, 1)), Some(stage_result(61_000, 1))],
+            )]),
+        );
+        prev_moved.stamp_observed_at(None, 100);
+
+        let mut results = rally_results(&[("alice", &[Some(61_000), Some(60_000)])]);
+        results.stamp_observed_at(Some(&prev_moved), 200);
+        let observed_at = |results: &RallyResults, stage_idx: usize| {
+            results.driver_results[0].stages[stage_idx]
+                .as_ref()
+                .unwrap()
+                .observed_at
+        };
+        assert_eq!(observed_at(&results, 0), Some(100));
+        assert_eq!(observed_at(&results, 1), Some(200));
+        assert!(prev.stage_result("alice", &stage(3)).is_none());
+        assert_eq!(
+            prev.stage_result("alice", &stage(2)).unwrap().time_ms,
+            62_000
+        );
+    }
+
+    /// What a [`Recorder`] saw: the fields of every span by span name, and
+    /// the message of every event.
+    #[derive(Default)]
+    struct Recorded {
+        spans: BTreeMap<String, BTreeMap<String, String>>,
+        messages: Vec<String>,
+    }
+
+    /// A subscriber that records what [`Recorded`] holds.
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Recorded>>);
+
+    /// The fields of one span or event, formatted with `Debug`.
+    #[derive(Default)]
+    struct Fields(BTreeMap<String, String>);
+
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .spans
+                .insert(span.metadata().name().to_string(), fields.0);
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            if let Some(message) = fields.0.remove("message") {
+                self.0.lock().unwrap().messages.push(message);
+            }
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    /// Run `f` and return what it traced.
+    fn recorded(f: impl FnOnce()) -> Recorded {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), f);
+        std::mem::take(&mut *recorder.0.lock().unwrap())
+    }
+
+    #[test]
+    fn get_rally_results_span_has_fields() {
+        let recorded = recorded(|| {
+            // Without any stages there's nothing to download
+            get_rally_results(
+                &[],
+                &[1, 2],
+                &["alice", "bob"],
+                None,
+                1,
+                &ProgressBar::hidden(),
+                &mut DownloadTimes::default(),
+            )
+            .unwrap();
+        });
+        let fields = &recorded.spans["get_rally_results"];
+        assert_eq!(fields["users"], "2");
+        assert_eq!(fields["stages"], "0");
+        assert_eq!(fields["area"], "None");
+        assert_eq!(fields["cache"], http::cache_enabled().to_string());
+    }
+
+    #[test]
+    fn unattempted_stages_finds_an_untouched_middle_stage() {
+        let results = rally_results(&[
+            ("alice", &[Some(61_000), None, Some(63_000)]),
+            ("bob", &[Some(62_000), None, None]),
+        ]);
+        assert_eq!(unattempted_stages(&results), [1]);
+    }
+
+    #[test]
+    fn tie_break_policies_order_a_tie_differently() {
+        // Same total, but bob has the better local ranks
+        let results = rally_results(&[
+            ("alice", &[Some(60_000), Some(70_000)]),
+            ("bob", &[Some(64_000), Some(66_000)]),
+            ("carol", &[Some(65_000), Some(67_000)]),
+        ]);
+        let order = |tie_break| {
+            split_times(&results, tie_break)
+                .0
+                .iter()
+                .map(|ft| (ft.user_name, ft.total_local_rank))
+                .collect_vec()
+        };
+        assert_eq!(
+            order(TieBreak::Alphabetical),
+            [("alice", 1), ("bob", 1), ("carol", 3)]
+        );
+        assert_eq!(
+            order(TieBreak::LocalRanks),
+            [("bob", 1), ("alice", 2), ("carol", 3)]
+        );
+    }
+
+    #[test]
+    fn completion_counts_finished_stages() {
+        let results = rally_results(&[
+            ("alice", &[Some(61_000), Some(62_000), Some(63_000)]),
+            ("bob", &[Some(61_000), None, Some(63_000)]),
+            ("carol", &[None, None, None]),
+        ]);
+        let completion = results
+            .driver_results
+            .iter()
+            .map(|driver| driver.completion(results.stages.len()))
+            .collect_vec();
+        assert_eq!(completion, [(3, 3), (2, 3), (0, 3)]);
+    }
+
+    #[test]
+    fn stamp_observed_at_stamps_new_times() {
+        let mut prev = rally_results(&[("alice", &[Some(61_000), None])]);
+        prev.stamp_observed_at(None, 100);
+
+        let mut results = rally_results(&[
+            ("alice", &[Some(61_000), Some(62_000)]),
+            ("bob", &[Some(63_000), None]),
+        ]);
+        results.stamp_observed_at(Some(&prev), 200);
+        let observed_at = results
+            .driver_results
+            .iter()
+            .map(|driver| {
+                driver
+                    .stages
+                    .iter()
+                    .map(|stage_result| stage_result.as_ref().and_then(|r| r.observed_at))
+                    .collect_vec()
+            })
+            .collect_vec();
+        assert_eq!(observed_at, [[Some(100), Some(200)], [Some(200), None]]);
+    }
+
+    #[test]
+    fn the_fastest_driver_isnt_always_the_most_consistent() {
+        let results = rally_results(&[
+            // Fastest in total, but loses a lot on stage 2
+            ("alice", &[Some(60_000), Some(70_000), Some(60_000)]),
+            // 10% off the pace everywhere
+            ("bob", &[Some(66_000), Some(66_000), Some(66_000)]),
+            ("carol", &[Some(80_000), Some(60_000), Some(80_000)]),
+        ]);
+        let (full_times, _, _) = split_times(&results, TieBreak::default());
+        let (_, fastest_stages) = fastest_times(&full_times, &results);
+        let weights = |total_rank, stage_wins, consistency| DriverOfTheRallyWeights {
+            total_rank,
+            stage_wins,
+            consistency,
+        };
+
+        let fastest =
+            driver_of_the_rally(&full_times, &fastest_stages, weights(1.0, 0.0, 0.0)).unwrap();
+        assert_eq!(fastest.user_name, "alice");
+        assert_eq!(fastest.total_rank, 1.0);
+        assert!(fastest.consistency < 1.0);
+
+        let consistent =
+            driver_of_the_rally(&full_times, &fastest_stages, weights(0.0, 0.0, 1.0)).unwrap();
+        assert_eq!(consistent.user_name, "bob");
+        assert_eq!(consistent.consistency, 1.0);
+        assert_eq!(consistent.stage_wins, 0.0);
+
+        assert!(
+            driver_of_the_rally(&full_times[..1], &fastest_stages, weights(1.0, 1.0, 1.0))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn driver_of_the_rally_survives_a_zero_time() {
+        let results = rally_results(&[
+            ("alice", &[Some(0), Some(60_000)]),
+            ("bob", &[Some(1_000), Some(61_000)]),
+        ]);
+        let (full_times, _, _) = split_times(&results, TieBreak::default());
+        let (_, fastest_stages) = fastest_times(&full_times, &results);
+
+        let dotr = driver_of_the_rally(
+            &full_times,
+            &fastest_stages,
+            DriverOfTheRallyWeights::default(),
+        )
+        .unwrap();
+        assert_eq!(dotr.user_name, "alice");
+        assert!(dotr.score.is_finite());
+        assert!(dotr.consistency.is_finite());
+    }
+
+    #[test]
+    fn sentinel_world_ranks_become_none() {
+        assert_eq!(valid_world_rank(0), None);
+        assert_eq!(valid_world_rank(usize::MAX), None);
+        assert_eq!(valid_world_rank(1), Some(1));
+
+        // Sentinels still sort the names, but aren't reported
+        let boards = vec![Some(vec![stage_result(61_000, 1), stage_result(62_000, 2)])];
+        let world_ranks = [vec![Some((0, None))], vec![Some((usize::MAX, None))]];
+        let drivers = match_drivers(&[stage(1)], &["alice", "bob"], boards, &world_ranks);
+        let alice = drivers["alice"][0].as_ref().unwrap();
+        assert_eq!((alice.time_ms, alice.world_rank), (61_000, None));
+        let bob = drivers["bob"][0].as_ref().unwrap();
+        assert_eq!((bob.time_ms, bob.world_rank), (62_000, None));
+    }
+
+    #[test]
+    fn percentile_of_known_distributions() {
+        let times = [60_000, 61_000, 62_000, 63_000, 64_000];
+        assert_eq!(percentile(60_000, &times), 100.0);
+        assert_eq!(percentile(62_000, &times), 50.0);
+        assert_eq!(percentile(64_000, &times), 0.0);
+        // Ties aren't faster than each other
+        assert_eq!(percentile(60_000, &[60_000, 60_000, 61_000]), 50.0);
+        assert_eq!(percentile(60_000, &[60_000]), 100.0);
+        assert_eq!(percentile(60_000, &[]), 100.0);
+    }
+
+    fn rally_config(stages: &[usize]) -> RallyConfig {
+        RallyConfig {
+            title: "test".to_string(),
+            area: Area::Kenya,
+            group: Group::GroupB,
+            weather: Weather::Dry,
+            direction: Direction::Forward,
+            directions: Vec::new(),
+            weathers: Vec::new(),
+            stages: stages.to_vec(),
+            precision: Precision::default(),
+        }
+    }
+
+    #[test]
+    fn from_config_checks_the_stages() {
+        let rally = Rally::from_config(&rally_config(&[1, 3])).unwrap();
+        assert_eq!(rally.stages.len(), 2);
+
+        let e = Rally::from_config(&rally_config(&[1, 7])).err().unwrap();
+        assert!(e.to_string().contains("has stage 7"), "{e}");
+        let e = Rally::from_config(&rally_config(&[2, 2])).err().unwrap();
+        assert!(e.to_string().contains("stage 2 more than once"), "{e}");
+        let e = Rally::from_config(&rally_config(&[])).err().unwrap();
+        assert!(e.to_string().contains("no stages"), "{e}");
+    }
+
+    #[test]
+    fn bogey_driver_is_the_clear_loser() {
+        let results = rally_results(&[
+            ("alice", &[Some(60_000), Some(70_000), Some(80_000)]),
+            ("bob", &[Some(61_000), Some(72_000), Some(79_000)]),
+            ("carol", &[Some(65_000), Some(75_000), None]),
+        ]);
+        // Last on the first two stages, and didn't finish the third
+        assert_eq!(bogey_driver(&results).as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn bogey_driver_needs_two_finishers() {
+        let results = rally_results(&[("alice", &[Some(60_000)]), ("bob", &[None])]);
+        assert_eq!(bogey_driver(&results), None);
+    }
+
+    #[test]
+    fn only_partial_finishers_have_no_fastest_total() {
+        let results = rally_results(&[
+            ("alice", &[Some(60_000), None]),
+            ("bob", &[None, Some(70_000)]),
+        ]);
+        let (full_times, partial_times, _) = split_times(&results, TieBreak::default());
+        assert!(full_times.is_empty());
+        let (fastest_total, fastest_stages) = fastest_times(&full_times, &results);
+        assert_eq!(fastest_total, None);
+        assert_eq!(fastest_stages, [Some(60_000), Some(70_000)]);
+        assert_eq!(
+            fastest_partial_totals(&partial_times),
+            BTreeMap::from([(1, 60_000)])
+        );
+    }
+
+    #[test]
+    fn stage_result_from_a_sample_entry() {
+        let entry = LeaderboardEntry {
+            car_id: 7,
+            score: 61_234,
+            rank: 3,
+        };
+        let result = stage_result_from_entry(&entry, Some(1_500), 1);
+        assert_eq!(result.car, 7);
+        assert_eq!(result.time_ms, 61_234);
+        assert_eq!(result.local_rank, 3);
+        assert_eq!(result.world_rank, Some(1_500));
+        assert_eq!(result.world_entries, None);
+        assert_eq!(result.observed_at, None);
+
+        // Scores in hundredths, and a sentinel world rank
+        let result = stage_result_from_entry(&entry, Some(0), 10);
+        assert_eq!(result.time_ms, 612_340);
+        assert_eq!(result.world_rank, None);
+    }
+
+    #[test]
+    fn standings_table_serializes_to_plain_data() {
+        let results = rally_results(&[
+            ("alice", &[Some(60_000), Some(70_000)]),
+            ("bob", &[Some(61_000), Some(69_000)]),
+            ("carol", &[Some(59_000), None]),
+        ]);
+        let standings = results.to_standings_table(TieBreak::default());
+        assert_eq!(
+            serde_json::to_value(&standings).unwrap(),
+            serde_json::json!([
+                {
+                    "name": "alice",
+                    "rank": 1,
+                    "total_ms": 130_000,
+                    "total_delta_ms": 0,
+                    "is_partial": false,
+                    "finished_stages": 2,
+                    "stages": [
+                        { "time_ms": 60_000, "delta_ms": 1_000, "car": 0, "local_rank": 2, "world_rank": null },
+                        { "time_ms": 70_000, "delta_ms": 1_000, "car": 0, "local_rank": 2, "world_rank": null },
+                    ],
+                },
+                {
+                    "name": "bob",
+                    "rank": 1,
+                    "total_ms": 130_000,
+                    "total_delta_ms": 0,
+                    "is_partial": false,
+                    "finished_stages": 2,
+                    "stages": [
+                        { "time_ms": 61_000, "delta_ms": 2_000, "car": 0, "local_rank": 3, "world_rank": null },
+                        { "time_ms": 69_000, "delta_ms": 0, "car": 0, "local_rank": 1, "world_rank": null },
+                    ],
+                },
+                {
+                    "name": "carol",
+                    "rank": null,
+                    "total_ms": 59_000,
+                    "total_delta_ms": 0,
+                    "is_partial": true,
+                    "finished_stages": 1,
+                    "stages": [
+                        { "time_ms": 59_000, "delta_ms": 0, "car": 0, "local_rank": 1, "world_rank": null },
+                        null,
+                    ],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn length_class_from_the_median_time() {
+        const MIN: usize = 60 * 1000;
+        let results = rally_results(&[
+            (
+                "alice",
+                &[Some(2 * MIN), Some(4 * MIN), Some(4 * MIN), Some(MIN)],
+            ),
+            (
+                "bob",
+                &[Some(2 * MIN), Some(4 * MIN), Some(5 * MIN), Some(MIN)],
+            ),
+            // An outlier doesn't move the median
+            (
+                "carol",
+                &[Some(9 * MIN), Some(4 * MIN), Some(6 * MIN), None],
+            ),
+        ]);
+        assert_eq!(stage_length_class(&results, 0), LengthClass::Short);
+        assert_eq!(stage_length_class(&results, 1), LengthClass::Medium);
+        assert_eq!(stage_length_class(&results, 2), LengthClass::Long);
+        // Only two finishers
+        assert_eq!(stage_length_class(&results, 3), LengthClass::Unknown);
+    }
+
+    #[test]
+    fn friends_urls_without_users_is_an_error() {
+        let platform = "steam".parse::<PlatformName>().unwrap().0;
+        let leaderboards = [(stage(1), platform)];
+        let e = friends_urls(&leaderboards, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "No users configured");
+        assert_eq!(friends_urls(&leaderboards, &[1, 2]).unwrap().len(), 1);
+        assert!(friends_urls(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn weather_distribution_of_mixed_rallys() {
+        let rally = |weathers: &[Weather]| Rally {
+            title: "test".to_string(),
+            stages: weathers
+                .iter()
+                .enumerate()
+                .map(|(i, weather)| {
+                    let (stage, group, _) = stage(i + 1);
+                    (stage, group, *weather)
+                })
+                .collect(),
+            precision: Precision::default(),
+        };
+        let rallys = [
+            rally(&[Weather::Dry, Weather::Wet, Weather::Dry]),
+            rally(&[Weather::Wet]),
+            rally(&[]),
+        ];
+        assert_eq!(
+            weather_distribution(&rallys),
+            BTreeMap::from([(Weather::Dry, 2), (Weather::Wet, 2)])
+        );
+        assert!(weather_distribution(&[]).is_empty());
+    }
+
+    #[test]
+    fn mis_scaled_times_are_warned_about() {
+        // Centiseconds read as milliseconds
+        let entry = LeaderboardEntry {
+            car_id: 0,
+            score: 6_123,
+            rank: 1,
+        };
+        let recorded = recorded(|| {
+            let boards = vec![Some(vec![
+                stage_result_from_entry(&entry, None, 1),
+                stage_result(61_000, 2),
+            ])];
+            match_drivers(
+                &[stage(1)],
+                &["alice", "bob"],
+                boards,
+                &[vec![Some((1, None))], vec![Some((2, None))]],
+            );
+        });
+        let warnings = &recorded.messages;
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(
+            warnings[0].starts_with("alice has an implausible time of 6123 ms"),
+            "{warnings:?}"
+        );
+
+        // Which the multiplier corrects
+        let time_ms = stage_result_from_entry(&entry, None, 10).time_ms;
+        assert!(PLAUSIBLE_STAGE_TIMES_MS.contains(&time_ms));
+    }
+
+    #[test]
+    fn remaining_stages_of_a_partial_finisher() {
+        let results = rally_results(&[
+            ("alice", &[Some(60_000), None, Some(62_000), None]),
+            (
+                "bob",
+                &[Some(61_000), Some(63_000), Some(64_000), Some(65_000)],
+            ),
+        ]);
+        let remaining = |name: &str| {
+            let driver = results
+                .driver_results
+                .iter()
+                .find(|driver| driver.name == name)
+                .unwrap();
+            remaining_stages(driver, &results.stages)
+                .iter()
+                .map(stage_key)
+                .collect_vec()
+        };
+        assert_eq!(
+            remaining("alice"),
+            [stage_key(&stage(2)), stage_key(&stage(4))]
+        );
+        assert!(remaining("bob").is_empty());
+    }
+
+    #[test]
+    fn change_since_each_combination() {
+        let orderings = [Ordering::Less, Ordering::Equal, Ordering::Greater];
+        // The previous value that makes 10 compare as `ordering` to it
+        let prev_of = |ordering: Ordering| match ordering {
+            Ordering::Less => 11,
+            Ordering::Equal => 10,
+            Ordering::Greater => 9,
+        };
+        for time in orderings {
+            for local_rank in orderings {
+                for world_rank in orderings {
+                    let current = StageResult {
+                        world_rank: Some(10),
+                        ..stage_result(10, 10)
+                    };
+                    let prev = StageResult {
+                        world_rank: Some(prev_of(world_rank)),
+                        ..stage_result(prev_of(time), prev_of(local_rank))
+                    };
+                    assert_eq!(
+                        current.change_since(&prev),
+                        Change {
+                            time,
+                            local_rank,
+                            world_rank
+                        }
+                    );
+                }
+            }
+        }
+
+        // Without world ranks on both sides there's nothing to compare
+        let ranked = StageResult {
+            world_rank: Some(5),
+            ..stage_result(10, 1)
+        };
+        let unranked = stage_result(10, 1);
+        assert_eq!(ranked.change_since(&unranked).world_rank, Ordering::Equal);
+        assert_eq!(unranked.change_since(&ranked).world_rank, Ordering::Equal);
+    }
+
+    #[test]
+    fn platform_names_round_trip() {
+        for name in ["steam", "Steam", "STEAM"] {
+            let platform = name.parse::<PlatformName>().unwrap();
+            assert_eq!(platform.to_string(), "steam");
+            let again = platform.to_string().parse::<PlatformName>().unwrap();
+            assert_eq!(
+                serde_json::to_value(again.0).unwrap(),
+                serde_json::to_value(platform.0).unwrap()
+            );
+        }
+        let e = "dreamcast"
+            .parse::<PlatformName>()
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.starts_with("Unknown platform \"dreamcast\""), "{e}");
+        // serde lists the valid platforms
+        assert!(e.to_lowercase().contains("steam"), "{e}");
+    }
+
+    #[test]
+    fn equal_times_are_ordered_by_name() {
+        let results = rally_results(&[
+            ("carol", &[Some(60_000), Some(70_000)]),
+            ("alice", &[Some(61_000), Some(70_000)]),
+            ("bob", &[Some(60_000), Some(71_000)]),
+        ]);
+        let order = |stage_idx: usize| {
+            results.stage_results[stage_idx]
+                .iter()
+                .map(|(name, stage_result)| (name.as_str(), stage_result.time_ms))
+                .collect_vec()
+        };
+        assert_eq!(
+            order(0),
+            [("bob", 60_000), ("carol", 60_000), ("alice", 61_000)]
+        );
+        assert_eq!(
+            order(1),
+            [("alice", 70_000), ("carol", 70_000), ("bob", 71_000)]
+        );
+
+        let (full_times, _, _) = split_times(&results, TieBreak::Alphabetical);
+        assert_eq!(
+            full_times
+                .iter()
+                .map(|ft| (ft.user_name, ft.total_local_rank))
+                .collect_vec(),
+            [("carol", 1), ("alice", 2), ("bob", 2)]
+        );
+        let (_, fastest_stages) = fastest_times(&full_times, &results);
+        assert_eq!(fastest_stages, [Some(60_000), Some(70_000)]);
+    }
+
+    #[test]
+    fn world_ranks_are_only_asked_of_known_drivers() {
+        let stages = [stage(1), stage(2), stage(3), stage(4)];
+        let users = ["alice", "bob", "carol"];
+        let prev = rally_results(&[
+            ("alice", &[Some(60_000), Some(70_000), None, None]),
+            ("bob", &[Some(61_000), None, None, None]),
+            ("carol", &[None, None, None, None]),
+        ]);
+        // Stage 1 has the same two drivers as last run, stage 2 has a new
+        // one, nobody has driven stage 3 and stage 4 failed to download
+        let entries = [Some(2), Some(2), Some(0), None];
+
+        assert_eq!(
+            rank_requests(&stages, &entries, &users, Some(&prev)),
+            [(0, 0), (0, 1), (1, 0), (1, 1), (1, 2)]
+        );
+        // Without a previous run everyone is asked about the driven stages
+        assert_eq!(rank_requests(&stages, &entries, &users, None).len(), 6);
+    }
+}
@@ -1,55 +1,304 @@
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use art_of_rally_leaderboard_api::{Platform, car_name};
-use art_of_rally_leaderboard_utils::config::Config;
-use art_of_rally_leaderboard_utils::table_utils::{format_delta, format_time};
+use art_of_rally_leaderboard_api::{Response, Stage, Weather, car_name};
+use art_of_rally_leaderboard_utils::config::{Config, NotificationStyle, StageSort};
+use art_of_rally_leaderboard_utils::history::{
+    Db, PersonalBests, StageRecords, inactive_drivers, latest_snapshot, load_snapshots,
+    progression, read_db,
+};
+use art_of_rally_leaderboard_utils::html::{html_page, html_page_updated_at, url_safe};
+use art_of_rally_leaderboard_utils::table_utils::{
+    Precision, format_delta, format_delta_with, format_percent_gap_with, format_signed_delta_with,
+    format_time, format_time_compact, format_time_with, pace_bucket,
+};
 use art_of_rally_leaderboard_utils::{
-    Rally, RallyResults, fastest_times, get_default_rallys, get_rally_results, split_times,
+    Change, FullTime, PlatformName, Rally, RallyResults, StageName, StageWins,
+    StageWithLeaderboard, StandingRow, WorldResults, bogey_driver, car_stats, download,
+    driver_of_the_rally, fastest_partial_totals, fastest_times, friends_urls, head_to_head,
+    percentile, remaining_stages, split_times, stage_length_class, stage_wins, unattempted_stages,
+    weather_distribution, world_top_percent,
 };
 use indexmap::IndexMap;
 use itertools::Itertools as _;
 use maud::{PreEscaped, html};
-use serde::{Deserialize, Serialize};
-use snafu::{ResultExt as _, Whatever};
+use serde::Serialize;
+use snafu::{ResultExt as _, Whatever, whatever};
 
-fn html_page<'a>(
-    header: &str,
-    body: impl IntoIterator<Item = &'a PreEscaped<String>>,
-) -> PreEscaped<String> {
-    html!(
-        (maud::DOCTYPE)
-        html {
-            head {
-                link rel="stylesheet" href="/style.css";
-                link rel="preconnect" href="https://fonts.googleapis.com";
-                link rel="preconnect" href="https://fonts.gstatic.com" crossorigin;
-                link rel="stylesheet" href="https://fonts.googleapis.com/css2?family=Atkinson+Hyperlegible+Next:ital,wght@0,200..800;1,200..800&display=swap";
-                link rel="stylesheet" href="https://fonts.googleapis.com/css2?family=Ubuntu+Mono:ital,wght@0,400;0,700;1,400;1,700&display=swap";
-            }
+/// Write a file by writing to `{path}.tmp` and then moving it into place, so a
+/// crash never leaves a half-written file at `path`.
+fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Like [`write_atomic`], but leaves the file alone if it already has exactly
+/// these contents, so its modification time only changes with it.
+fn write_if_changed(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    if std::fs::read(path.as_ref()).is_ok_and(|existing| existing == contents.as_ref()) {
+        return Ok(());
+    }
+    write_atomic(path, contents)
+}
+
+/// How times are shown in the rally tables. Each mode gets its own page.
+#[derive(Clone, Copy, PartialEq)]
+enum TableMode {
+    /// Interval to the fastest time.
+    Interval,
+    /// The time itself.
+    Absolute,
+    /// The time itself, and how much slower than the fastest time it is in
+    /// percent.
+    Combined,
+}
 
-            body {
-                h1 { (header) }
+impl TableMode {
+    const ALL: [TableMode; 3] = [
+        TableMode::Interval,
+        TableMode::Absolute,
+        TableMode::Combined,
+    ];
 
-                @for part in body {
-                    (part)
-                }
+    fn name(self) -> &'static str {
+        match self {
+            TableMode::Interval => "interval time",
+            TableMode::Absolute => "absolute time",
+            TableMode::Combined => "combined",
+        }
+    }
+
+    fn page(self) -> &'static str {
+        match self {
+            TableMode::Interval => "index.html",
+            TableMode::Absolute => "absolute.html",
+            TableMode::Combined => "combined.html",
+        }
+    }
 
-                p {
-                    "last updated: " (chrono::Utc::now().format("%F %R %Z"))
+    /// Links to the pages of the other modes.
+    fn nav(self) -> PreEscaped<String> {
+        html!(
+            div {
+                @for (i, mode) in TableMode::ALL.into_iter().enumerate() {
+                    @if i > 0 { " | " }
+                    @if mode == self {
+                        (mode.name())
+                    } @else {
+                        a href=(format!("/{}", mode.page())) { (mode.name()) }
+                    }
                 }
             }
+        )
+    }
+
+    /// Format a time that isn't the fastest one.
+    fn format(self, time: usize, fast: usize, long: bool, precision: Precision) -> String {
+        match self {
+            TableMode::Interval => format_delta_with(time, fast, long, precision),
+            TableMode::Absolute => format_time_with(time, long, precision),
+            TableMode::Combined => {
+                format!(
+                    "{} ({})",
+                    format_time_with(time, long, precision),
+                    format_percent_gap_with(time, fast, precision)
+                )
+            }
         }
+    }
+}
+
+/// "set on <date>" under a time, if enabled with `show_set_dates`. The date is
+/// absolute rather than "today", so the page only changes with the times.
+fn set_on(config: &Config, observed_at: Option<i64>) -> PreEscaped<String> {
+    if !config.show_set_dates {
+        return html!();
+    }
+    let Some(date) = observed_at
+        .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+        .map(|date| date.date_naive())
+    else {
+        return html!();
+    };
+    html!(
+        br;
+        span class="set-on" { "set on " (date) }
     )
 }
 
-fn url_safe(s: &str) -> String {
-    s.to_lowercase().replace(" ", "-")
+/// The name of the page a stage's results are on. With
+/// `combine_stage_variants` every weather and direction of a stage shares one.
+fn stage_page(config: &Config, stage: &Stage, weather: Weather) -> String {
+    if config.combine_stage_variants {
+        stage.to_string()
+    } else {
+        format!("{} {weather}", StageName(stage))
+    }
+}
+
+/// Total results table for a rally. (stages) x (drivers).
+fn rally_table(
+    rally: &Rally,
+    results: &RallyResults,
+    config: &Config,
+    mode: TableMode,
+    inactive: &BTreeSet<String>,
+) -> PreEscaped<String> {
+    let (mut full_times, mut partial_times, none_times) = split_times(results, config.tie_break);
+    full_times.retain(|ft| !inactive.contains(ft.user_name));
+    partial_times.retain(|pt| !inactive.contains(pt.user_name));
+    // Configured drivers who haven't driven the rally at all, so they can see
+    // that they're on the list
+    let (_, user_names) = config.users();
+    let did_not_start = user_names
+        .into_iter()
+        .filter(|name| {
+            !results
+                .driver_results
+                .iter()
+                .any(|driver| driver.name == *name)
+        })
+        .chain(none_times)
+        .filter(|name| !inactive.contains(*name))
+        .sorted()
+        .dedup()
+        .collect_vec();
+    if config.pin_focus_driver {
+        // Stable, so everyone else keeps their order
+        full_times.sort_by_key(|ft| !config.is_focus(ft.user_name));
+        partial_times.sort_by_key(|pt| !config.is_focus(pt.user_name));
+    }
+    let (fastest_total, _) = fastest_times(&full_times, results);
+    // From the same drivers as the total, so a hidden driver's time doesn't
+    // show up as an interval nobody in the table drove
+    let fastest_stages = (0..results.stages.len())
+        .map(|i| {
+            let full = full_times.iter().map(|ft| ft.stage_times[i]);
+            let partial = partial_times.iter().filter_map(|pt| pt.stage_times[i]);
+            full.chain(partial).min()
+        })
+        .collect_vec();
+    let fastest_partials = fastest_partial_totals(&partial_times);
+    let precision = rally.precision;
+    let observed_at = |name: &str, stage_idx: usize| {
+        results
+            .driver_results
+            .iter()
+            .find(|driver| driver.name == name)?
+            .stages
+            .get(stage_idx)?
+            .as_ref()?
+            .observed_at
+    };
+    // Only the combined table shows percentages
+    let pace = |time: usize, fast: usize| {
+        (mode == TableMode::Combined)
+            .then(|| format!("pace-{}", pace_bucket(time, fast, &config.pace_buckets)))
+    };
+    let completion: BTreeMap<_, _> = results
+        .driver_results
+        .iter()
+        .map(|driver| {
+            (
+                driver.name.as_str(),
+                driver.completion(results.stages.len()),
+            )
+        })
+        .collect();
+
+    html!(
+        table class="rally" {
+            thead {
+                th { }
+                th { "driver" }
+                th { }
+                th { "total" }
+                @for (i, (stage, _group, weather)) in rally.stages.iter().enumerate() {
+                    @let length = stage_length_class(results, i);
+                    th {
+                        a href=(format!("/{}.html", url_safe(&stage_page(config, stage, *weather)))) { (StageName(stage)) " (" (weather) ")" }
+                        " "
+                        span class="length" title=(format!("{} stage", length.name())) { (length.icon()) }
+                    }
+                }
+            }
+            @for ft in &full_times {
+                tr.focus[config.is_focus(ft.user_name)] {
+                    td { (ft.total_local_rank) "." }
+                    td { a href=(format!("/{}.html", url_safe(ft.user_name))) { (ft.user_name) } }
+                    @let (finished, total) = completion[ft.user_name];
+                    td { (finished) "/" (total) }
+                    @let total = ft.total_time;
+                    @let fastest_total = fastest_total.expect("there are full times");
+                    @if total == fastest_total {
+                        td class="fastest" { (format_time_with(total, true, precision)) }
+                    } @else {
+                        td class=[pace(total, fastest_total)] { (mode.format(total, fastest_total, true, precision)) }
+                    }
+                    @for (i, time) in ft.stage_times.iter().copied().enumerate() {
+                        @let fast = fastest_stages[i].unwrap();
+                        @let set_on = set_on(config, observed_at(ft.user_name, i));
+                        @if time == fast {
+                            td class="fastest" { (format_time_with(time, false, precision)) (set_on) }
+                        } @else {
+                            td class=[pace(time, fast)] { (mode.format(time, fast, false, precision)) (set_on) }
+                        }
+                    }
+                }
+            }
+            @for pt in &partial_times {
+                tr.focus[config.is_focus(pt.user_name)] {
+                    td { }
+                    td { a href=(format!("/{}.html", url_safe(pt.user_name))) { (pt.user_name) } }
+                    @let (finished, total) = completion[pt.user_name];
+                    td { "* " (finished) "/" (total) }
+                    @let total = pt.total_time;
+                    // Compared to the fastest partial with as many finished stages
+                    @let fastest_partial = fastest_partials[&pt.finished_stages];
+                    @if total == fastest_partial {
+                        td { (format_time_with(total, true, precision)) }
+                    } @else {
+                        td class=[pace(total, fastest_partial)] { (mode.format(total, fastest_partial, true, precision)) }
+                    }
+                    @for (i, time) in pt.stage_times.iter().copied().enumerate() {
+                        @if let Some(time) = time {
+                            @let fast = fastest_stages[i].unwrap();
+                            @let set_on = set_on(config, observed_at(pt.user_name, i));
+                            @if time == fast {
+                                td class="fastest" { (format_time_with(time, false, precision)) (set_on) }
+                            } @else {
+                                td class=[pace(time, fast)] { (mode.format(time, fast, false, precision)) (set_on) }
+                            }
+                        } @else {
+                            td { }
+                        }
+                    }
+                }
+            }
+            @for name in &did_not_start {
+                tr."did-not-start".focus[config.is_focus(name)] {
+                    td { }
+                    td { a href=(format!("/{}.html", url_safe(name))) { (name) } }
+                    td { "0/" (rally.stages.len()) }
+                    td { "did not start" }
+                    @for _ in &rally.stages {
+                        td { }
+                    }
+                }
+            }
+        }
+    )
 }
 
 type RallyName = String;
-type StageName = String;
+type StageTitle = String;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum Row {
     // Rendered as `> {rank} {name} {time}`
     FirstTime {
@@ -63,6 +312,7 @@ enum Row {
         name: String,
         time: usize,
         prev: usize,
+        prev_rank: usize,
     },
     // Rendered as `~ {rank} {name} {time} {delta}`
     TimeImproved {
@@ -77,12 +327,21 @@ enum Row {
         name: String,
         time: usize,
         prev: usize,
+        prev_rank: usize,
     },
     // Rendered as `v {rank} {name} {time}`
     RankDecreased {
         rank: usize,
         name: String,
         time: usize,
+        prev_rank: usize,
+    },
+    // Rendered as `! {rank} {name} {time} overtook {rival}`
+    Overtook {
+        rank: usize,
+        name: String,
+        time: usize,
+        rival: String,
     },
     // Rendered as `  {rank} {name} {time}` if active is true
     Unchanged {
@@ -94,6 +353,60 @@ enum Row {
 }
 
 impl Row {
+    /// The row for a time that was driven in the previous run too.
+    /// Improvements smaller than `threshold` ms that don't change the rank
+    /// count as unchanged.
+    fn from_change(
+        change: Change,
+        rank: usize,
+        name: String,
+        time: usize,
+        prev: usize,
+        prev_rank: usize,
+        threshold: usize,
+    ) -> Row {
+        match (change.time, change.local_rank) {
+            (Ordering::Less, Ordering::Equal) if prev - time < threshold => Row::Unchanged {
+                active: false,
+                rank,
+                name,
+                time,
+            },
+            (Ordering::Less, Ordering::Less) => Row::TimeImprovedRankIncreased {
+                rank,
+                name,
+                time,
+                prev,
+                prev_rank,
+            },
+            (Ordering::Less, Ordering::Equal) => Row::TimeImproved {
+                rank,
+                name,
+                time,
+                prev,
+            },
+            (Ordering::Less, Ordering::Greater) => Row::TimeImprovedRankDecreased {
+                rank,
+                name,
+                time,
+                prev,
+                prev_rank,
+            },
+            (_, Ordering::Greater) => Row::RankDecreased {
+                rank,
+                name,
+                time,
+                prev_rank,
+            },
+            _ => Row::Unchanged {
+                active: false,
+                rank,
+                name,
+                time,
+            },
+        }
+    }
+
     fn rank(&self) -> usize {
         match self {
             Row::FirstTime { rank, .. } => *rank,
@@ -101,6 +414,7 @@ impl Row {
             Row::TimeImproved { rank, .. } => *rank,
             Row::TimeImprovedRankDecreased { rank, .. } => *rank,
             Row::RankDecreased { rank, .. } => *rank,
+            Row::Overtook { rank, .. } => *rank,
             Row::Unchanged { rank, .. } => *rank,
         }
     }
@@ -112,10 +426,61 @@ impl Row {
             Row::TimeImproved { name, .. } => name,
             Row::TimeImprovedRankDecreased { name, .. } => name,
             Row::RankDecreased { name, .. } => name,
+            Row::Overtook { name, .. } => name,
             Row::Unchanged { name, .. } => name,
         }
     }
 
+    fn time(&self) -> usize {
+        match self {
+            Row::FirstTime { time, .. } => *time,
+            Row::TimeImprovedRankIncreased { time, .. } => *time,
+            Row::TimeImproved { time, .. } => *time,
+            Row::TimeImprovedRankDecreased { time, .. } => *time,
+            Row::RankDecreased { time, .. } => *time,
+            Row::Overtook { time, .. } => *time,
+            Row::Unchanged { time, .. } => *time,
+        }
+    }
+
+    /// The previous time, if the time improved.
+    fn prev(&self) -> Option<usize> {
+        match self {
+            Row::TimeImprovedRankIncreased { prev, .. } => Some(*prev),
+            Row::TimeImproved { prev, .. } => Some(*prev),
+            Row::TimeImprovedRankDecreased { prev, .. } => Some(*prev),
+            Row::FirstTime { .. }
+            | Row::RankDecreased { .. }
+            | Row::Overtook { .. }
+            | Row::Unchanged { .. } => None,
+        }
+    }
+
+    /// The previous rank, if the rank changed.
+    fn prev_rank(&self) -> Option<usize> {
+        match self {
+            Row::TimeImprovedRankIncreased { prev_rank, .. } => Some(*prev_rank),
+            Row::TimeImprovedRankDecreased { prev_rank, .. } => Some(*prev_rank),
+            Row::RankDecreased { prev_rank, .. } => Some(*prev_rank),
+            Row::FirstTime { .. }
+            | Row::TimeImproved { .. }
+            | Row::Overtook { .. }
+            | Row::Unchanged { .. } => None,
+        }
+    }
+
+    fn marker(&self) -> &'static str {
+        match self {
+            Row::FirstTime { .. } => ">",
+            Row::TimeImprovedRankIncreased { .. } => "^",
+            Row::TimeImproved { .. } => "~",
+            Row::TimeImprovedRankDecreased { .. } => "v",
+            Row::RankDecreased { .. } => "v",
+            Row::Overtook { .. } => "!",
+            Row::Unchanged { .. } => " ",
+        }
+    }
+
     fn is_unchanged(&self) -> bool {
         matches!(self, Row::Unchanged { .. })
     }
@@ -135,6 +500,7 @@ impl Row {
                 name,
                 time,
                 prev,
+                ..
             } => Some(format!(
                 "{}^ {}.  {:name_width$}  {}  {}",
                 " ".repeat(indent),
@@ -163,6 +529,7 @@ impl Row {
                 name,
                 time,
                 prev,
+                ..
             } => Some(format!(
                 "{}v {}.  {:name_width$}  {}  {}",
                 " ".repeat(indent),
@@ -172,7 +539,9 @@ impl Row {
                 format_delta(*time, *prev, false),
                 name_width = name_width,
             )),
-            Row::RankDecreased { rank, name, time } => Some(format!(
+            Row::RankDecreased {
+                rank, name, time, ..
+            } => Some(format!(
                 "{}v {}.  {:name_width$}  {}",
                 " ".repeat(indent),
                 rank,
@@ -180,6 +549,20 @@ impl Row {
                 format_time(*time, false),
                 name_width = name_width
             )),
+            Row::Overtook {
+                rank,
+                name,
+                time,
+                rival,
+            } => Some(format!(
+                "{}! {}.  {:name_width$}  {}  overtook {}",
+                " ".repeat(indent),
+                rank,
+                name,
+                format_time(*time, false),
+                rival,
+                name_width = name_width
+            )),
             Row::Unchanged {
                 active: true,
                 rank,
@@ -199,17 +582,65 @@ impl Row {
 }
 
 // { rally => (total_time, { stage => stage_time }) }
-type NotificationTable = IndexMap<RallyName, (Vec<Row>, IndexMap<StageName, Vec<Row>>)>;
+type NotificationTable = IndexMap<RallyName, (Vec<Row>, IndexMap<StageTitle, Vec<Row>>)>;
 
-fn send_notification(notifications: &NotificationTable, webhook_url: &str) {
-    if notifications
-        .values()
-        .flat_map(|(rally, stages)| rally.iter().chain(stages.values().flatten()))
-        .all(Row::is_unchanged)
-    {
-        return;
+/// The recap of what changed, or `None` if nothing did.
+fn notification_message(notifications: &NotificationTable) -> Option<String> {
+    let sections = notification_sections(notifications);
+    (!sections.is_empty()).then(|| sections.concat())
+}
+
+/// The biggest improvement on a stage and the biggest climb in the
+/// standings, or `None` if nobody improved.
+fn headline(notifications: &NotificationTable) -> Option<String> {
+    let improvement = notifications
+        .iter()
+        .flat_map(|(rally, (_, stages))| {
+            stages.iter().flat_map(move |(stage, rows)| {
+                rows.iter().filter_map(move |row| {
+                    let prev = row.prev()?;
+                    Some((prev - row.time(), row.name(), stage, rally))
+                })
+            })
+        })
+        .max_by_key(|(delta, ..)| *delta);
+    let climb = notifications
+        .iter()
+        .flat_map(|(rally, (totals, stages))| {
+            totals.iter().map(move |row| (row, rally.as_str())).chain(
+                stages
+                    .iter()
+                    .flat_map(|(stage, rows)| rows.iter().map(move |row| (row, stage.as_str()))),
+            )
+        })
+        .filter_map(|(row, place)| match row {
+            Row::TimeImprovedRankIncreased {
+                rank,
+                name,
+                prev_rank,
+                ..
+            } => Some((prev_rank - rank, name, place, *prev_rank, *rank)),
+            _ => None,
+        })
+        .max_by_key(|(climb, ..)| *climb);
+
+    let mut headline = String::new();
+    if let Some((delta, name, stage, rally)) = improvement {
+        headline += &format!(
+            "biggest improvement: {name} on {stage} ({rally}), -{}\n",
+            format_time(delta, false)
+        );
+    }
+    if let Some((_, name, place, prev_rank, rank)) = climb {
+        headline += &format!("biggest climb: {name} in {place}, {prev_rank}. -> {rank}.\n");
     }
-    let mut message = "```".to_string();
+    (!headline.is_empty()).then_some(headline)
+}
+
+/// The recap in sections that can be sent separately: a headline and the
+/// totals of each rally and each stage. Empty if nothing changed.
+fn notification_sections(notifications: &NotificationTable) -> Vec<String> {
+    let mut sections = headline(notifications).into_iter().collect_vec();
     for (rally_name, (rally, stages)) in notifications {
         // Skip rallys where all rows are unchanged
         if rally
@@ -219,30 +650,216 @@ fn send_notification(notifications: &NotificationTable, webhook_url: &str) {
         {
             continue;
         }
-        message += &format!("\n{rally_name}\n");
-        let name_width = rally.iter().map(|row| row.name().len()).max().unwrap();
+        let mut section = format!("\n{rally_name}\n");
+        let name_width = rally.iter().map(|row| row.name().len()).max().unwrap_or(0);
         for row in rally {
             if let Some(row_message) = row.message(2, name_width) {
-                message += &row_message;
-                message += "\n";
+                section += &row_message;
+                section += "\n";
             }
         }
+        sections.push(section);
         for (stage, rows) in stages {
             // Skip stages where all rows are unchanged
             if rows.iter().all(Row::is_unchanged) {
                 continue;
             }
-            message += &format!("  {stage}\n");
+            let mut section = format!("  {stage}\n");
+            let name_width = rows.iter().map(|row| row.name().len()).max().unwrap();
             for row in rows {
-                let name_width = rows.iter().map(|row| row.name().len()).max().unwrap();
                 if let Some(row_message) = row.message(4, name_width) {
-                    message += &row_message;
-                    message += "\n";
+                    section += &row_message;
+                    section += "\n";
+                }
+            }
+            sections.push(section);
+        }
+    }
+    sections
+}
+
+/// Like [`notification_sections`] but with one line per driver and rally,
+/// summing up how much they improved over all stages, how many stages they
+/// drove for the first time and how their rank in the rally changed. One
+/// section per rally, after the headline.
+fn compact_notification_sections(notifications: &NotificationTable) -> Vec<String> {
+    #[derive(Default)]
+    struct Summary {
+        improved: usize,
+        time: usize,
+        prev: usize,
+        first_times: usize,
+    }
+
+    let mut sections = headline(notifications).into_iter().collect_vec();
+    for (rally_name, (rally, stages)) in notifications {
+        let mut drivers: IndexMap<&str, Summary> = IndexMap::new();
+        for row in stages.values().flatten() {
+            if let Row::FirstTime { .. } = row {
+                drivers.entry(row.name()).or_default().first_times += 1;
+            } else if let Some(prev) = row.prev() {
+                let driver = drivers.entry(row.name()).or_default();
+                driver.improved += 1;
+                driver.time += row.time();
+                driver.prev += prev;
+            }
+        }
+        // Drivers whose rank changed without a new time of their own
+        for row in rally {
+            if matches!(row, Row::FirstTime { .. }) || row.prev_rank().is_some() {
+                drivers.entry(row.name()).or_default();
+            }
+        }
+        if drivers.is_empty() {
+            continue;
+        }
+        // In the order of the rally standings
+        drivers.sort_by_cached_key(|name, _| {
+            rally
+                .iter()
+                .position(|row| row.name() == *name)
+                .unwrap_or(usize::MAX)
+        });
+
+        let mut section = format!("\n{rally_name}\n");
+        let name_width = drivers.keys().map(|name| name.len()).max().unwrap_or(0);
+        for (name, summary) in drivers {
+            let total = rally
+                .iter()
+                .find(|row| row.name() == name && !row.is_unchanged());
+            let stages = |n: usize| if n == 1 { "stage" } else { "stages" };
+            let mut parts = Vec::new();
+            if summary.improved > 0 {
+                parts.push(format!(
+                    "-{} on {} {}",
+                    format_time_compact(summary.prev - summary.time),
+                    summary.improved,
+                    stages(summary.improved)
+                ));
+            }
+            if summary.first_times > 0 {
+                parts.push(format!(
+                    "{} new {}",
+                    summary.first_times,
+                    stages(summary.first_times)
+                ));
+            }
+            match total {
+                Some(Row::FirstTime { rank, .. }) => {
+                    parts.push(format!("first full time, {rank}."));
                 }
+                Some(row) => {
+                    if let Some(prev_rank) = row.prev_rank() {
+                        let climb = prev_rank as i64 - row.rank() as i64;
+                        parts.push(format!(
+                            "{climb:+} {}",
+                            if climb.abs() == 1 { "rank" } else { "ranks" }
+                        ));
+                    }
+                }
+                None => {}
+            }
+            let marker = total.map_or(if summary.improved > 0 { "~" } else { ">" }, Row::marker);
+            section += &format!("  {marker} {name:name_width$}  {}\n", parts.join(", "));
+        }
+        sections.push(section);
+    }
+    sections
+}
+
+/// Discord doesn't accept messages longer than this.
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+/// Pack sections into as few messages as possible with at most `limit` bytes
+/// each, in order. Sections that don't fit in one message on their own are
+/// split between lines, and lines that don't fit on their own are split
+/// wherever they reach the limit.
+fn chunk_sections(sections: &[String], limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for section in sections {
+        let pieces = if section.len() <= limit {
+            vec![section.as_str()]
+        } else {
+            section
+                .split_inclusive('\n')
+                .flat_map(|line| split_at_limit(line, limit))
+                .collect_vec()
+        };
+        for piece in pieces {
+            if !chunk.is_empty() && chunk.len() + piece.len() > limit {
+                chunks.push(std::mem::take(&mut chunk));
             }
+            chunk += piece;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// `s` in pieces of at most `limit` bytes, split between characters.
+fn split_at_limit(mut s: &str, limit: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    while s.len() > limit {
+        let mut end = limit;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            // A single character over the limit can't be split any further
+            end = s.chars().next().map_or(s.len(), char::len_utf8);
         }
+        let (piece, rest) = s.split_at(end);
+        pieces.push(piece);
+        s = rest;
     }
-    message += "```";
+    pieces.push(s);
+    pieces
+}
+
+/// The Discord ids of drivers with a new or improved stage time, which
+/// includes anyone who took the lead on a stage.
+fn mentioned_ids(
+    notifications: &NotificationTable,
+    discord_ids: &BTreeMap<String, u64>,
+) -> Vec<u64> {
+    notifications
+        .values()
+        .flat_map(|(_, stages)| stages.values().flatten())
+        .filter(|row| matches!(row, Row::FirstTime { .. }) || row.prev().is_some())
+        .filter_map(|row| discord_ids.get(row.name()).copied())
+        .unique()
+        .collect()
+}
+
+/// A message in a code block. The fence gets a line of its own, otherwise
+/// Discord takes the first word of the message for the language.
+fn fence(message: &str) -> String {
+    format!("```\n{message}```")
+}
+
+/// With `dry_run` the messages are only printed, not sent.
+///
+/// Drivers in `discord_ids` who improved a time are mentioned after the last
+/// message, since mentions don't work inside code blocks.
+fn send_notification(
+    notifications: &NotificationTable,
+    webhook_url: &str,
+    style: NotificationStyle,
+    discord_ids: &BTreeMap<String, u64>,
+    dry_run: bool,
+) {
+    let sections = match style {
+        NotificationStyle::Detailed => notification_sections(notifications),
+        NotificationStyle::Compact => compact_notification_sections(notifications),
+    };
+    let mentioned = mentioned_ids(notifications, discord_ids);
+    let mentions = mentioned.iter().map(|id| format!("\n<@{id}>")).concat();
+    // Each message is fenced on its own, and the mentions go after the last
+    let fences = fence("").len();
+    let messages = chunk_sections(&sections, DISCORD_MESSAGE_LIMIT - fences - mentions.len());
 
     #[derive(Serialize)]
     struct WebhookMessage {
@@ -250,91 +867,255 @@ fn send_notification(notifications: &NotificationTable, webhook_url: &str) {
         allowed_mentions: HashMap<String, Vec<String>>,
     }
 
-    println!("{message}");
-    println!("sending notification...");
-    match ureq::post(webhook_url).send_json(&WebhookMessage {
-        content: message,
-        allowed_mentions: [("parse".to_string(), vec![])].into_iter().collect(),
-    }) {
-        Ok(mut r) => println!("{:?}: {:?}", r.status(), r.body_mut().read_to_string()),
-        Err(e) => println!("{e:?}"),
+    let last = messages.len().saturating_sub(1);
+    for (i, message) in messages.into_iter().enumerate() {
+        let mut message = fence(&message);
+        let mut users = Vec::new();
+        if i == last && !mentioned.is_empty() {
+            message += &mentions;
+            users = mentioned.iter().map(u64::to_string).collect();
+        }
+        println!("{message}");
+        if dry_run {
+            println!("dry run, not sending notification");
+            continue;
+        }
+        println!("sending notification...");
+        let status = match ureq::post(webhook_url).send_json(&WebhookMessage {
+            content: message.clone(),
+            allowed_mentions: [("parse".to_string(), vec![]), ("users".to_string(), users)]
+                .into_iter()
+                .collect(),
+        }) {
+            Ok(mut r) => {
+                println!("{:?}: {:?}", r.status(), r.body_mut().read_to_string());
+                Ok(r.status().as_u16())
+            }
+            Err(ureq::Error::StatusCode(status)) => {
+                println!("{status}");
+                Ok(status)
+            }
+            Err(e) => {
+                println!("{e:?}");
+                Err(e.to_string())
+            }
+        };
+        log_notification(notifications, &message, status);
+    }
+}
+
+/// Append a record of a sent notification to `data/notifications.jsonl`:
+/// when it was sent, the rallys and stages with changes, the message and how
+/// the webhook responded. Failing to write it only warns.
+fn log_notification(notifications: &NotificationTable, content: &str, status: Result<u16, String>) {
+    use std::io::Write as _;
+
+    #[derive(Serialize)]
+    struct NotificationRecord<'a> {
+        ts: i64,
+        // { rally => stages with changes }
+        rallys: IndexMap<&'a str, Vec<&'a str>>,
+        content: &'a str,
+        status: Option<u16>,
+        error: Option<String>,
+    }
+
+    let rallys = notifications
+        .iter()
+        .map(|(rally, (_, stages))| {
+            let stages = stages
+                .iter()
+                .filter(|(_, rows)| !rows.iter().all(Row::is_unchanged))
+                .map(|(stage, _)| stage.as_str())
+                .collect_vec();
+            (rally.as_str(), stages)
+        })
+        .collect();
+    let (status, error) = match status {
+        Ok(status) => (Some(status), None),
+        Err(e) => (None, Some(e)),
+    };
+    let record = NotificationRecord {
+        ts: chrono::Utc::now().timestamp(),
+        rallys,
+        content,
+        status,
+        error,
+    };
+    let path = "data/notifications.jsonl";
+    let written = serde_json::to_string(&record)
+        .map_err(std::io::Error::from)
+        .and_then(|line| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"))
+        });
+    if let Err(e) = written {
+        tracing::warn!("couldn't write {path}\n{e}");
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct Db {
-    rallys: Vec<Rally>,
-    results: Vec<RallyResults>,
-    platform: Platform,
-    user_ids: Vec<u64>,
-    user_names: Vec<String>,
+/// Send each rally's notifications to the webhooks configured for it, see
+/// [`routed_notifications`].
+fn route_notifications(notifications: &NotificationTable, config: &Config, dry_run: bool) {
+    for (webhook_url, notifications) in &routed_notifications(notifications, config) {
+        send_notification(
+            notifications,
+            webhook_url,
+            config.notification_style,
+            &config.discord_ids,
+            dry_run,
+        );
+    }
 }
 
-fn download(
-    rallys: Vec<Rally>,
-    platform: Platform,
-    user_ids: Vec<u64>,
-    user_names: Vec<&str>,
-) -> Result<Db, Whatever> {
-    let mut results = Vec::new();
-    for rally in &rallys {
-        let leaderboards = rally
-            .stages
-            .iter()
-            .copied()
-            .map(|stage| (stage, platform))
-            .collect_vec();
-        results.push(get_rally_results(&leaderboards, &user_ids, &user_names)?);
+/// The notifications for each webhook: each rally's go to the webhooks
+/// configured for it in `rally_webhooks`, or to `webhook_url` if it has none.
+fn routed_notifications<'c>(
+    notifications: &NotificationTable,
+    config: &'c Config,
+) -> IndexMap<&'c str, NotificationTable> {
+    let mut routed: IndexMap<&str, NotificationTable> = IndexMap::new();
+    for (rally, rows) in notifications {
+        let webhooks = match config.rally_webhooks.get(rally) {
+            Some(webhooks) => webhooks.iter().map(String::as_str).collect_vec(),
+            None => vec![config.webhook_url.as_str()],
+        };
+        for webhook in webhooks {
+            routed
+                .entry(webhook)
+                .or_default()
+                .insert(rally.clone(), rows.clone());
+        }
     }
+    routed
+}
 
-    Ok(Db {
-        rallys,
-        results,
-        platform,
-        user_ids,
-        user_names: user_names.into_iter().map(str::to_string).collect(),
+/// Shows the changes from the latest run to visitors who haven't seen them yet,
+/// using localStorage to remember when they last visited.
+const WHATS_NEW_SCRIPT: &str = r#"
+const banner = document.getElementById("whats-new");
+const updated = Number(banner.dataset.updated);
+const lastSeen = Number(localStorage.getItem("last-seen"));
+if (lastSeen && lastSeen < updated) {
+  banner.hidden = false;
+}
+localStorage.setItem("last-seen", updated);
+"#;
+
+/// `updated_at` is when the results last changed, which visitors compare with
+/// when they last visited.
+fn whats_new_banner(notifications: &NotificationTable, updated_at: i64) -> PreEscaped<String> {
+    let mut changes = Vec::new();
+    for (rally_name, (rally, stages)) in notifications {
+        for row in rally.iter().filter(|row| !row.is_unchanged()) {
+            changes.extend(row.message(0, 0).map(|msg| format!("{rally_name}: {msg}")));
+        }
+        for (stage, rows) in stages {
+            for row in rows.iter().filter(|row| !row.is_unchanged()) {
+                changes.extend(
+                    row.message(0, 0)
+                        .map(|msg| format!("{rally_name}, {stage}: {msg}")),
+                );
+            }
+        }
+    }
+    if changes.is_empty() {
+        return html!();
+    }
+
+    html!(
+        div #whats-new data-updated=(updated_at) hidden {
+            details {
+                summary { "what's new since your last visit" }
+                ul {
+                    @for change in &changes {
+                        li { (change) }
+                    }
+                }
+            }
+        }
+        script { (PreEscaped(WHATS_NEW_SCRIPT)) }
+    )
+}
+
+/// An overtake row if two rivals swapped places, given their previous and
+/// current (rank, time). Both need a rank both times.
+fn overtake(
+    rivals: &[String; 2],
+    prev: [Option<(usize, usize)>; 2],
+    now: [Option<(usize, usize)>; 2],
+) -> Option<Row> {
+    let [Some((prev_a, _)), Some((prev_b, _))] = prev else {
+        return None;
+    };
+    let [Some(now_a), Some(now_b)] = now else {
+        return None;
+    };
+    let ((rank, time), name, rival) = if prev_a > prev_b && now_a.0 < now_b.0 {
+        (now_a, &rivals[0], &rivals[1])
+    } else if prev_b > prev_a && now_b.0 < now_a.0 {
+        (now_b, &rivals[1], &rivals[0])
+    } else {
+        return None;
+    };
+    Some(Row::Overtook {
+        rank,
+        name: name.clone(),
+        time,
+        rival: rival.clone(),
     })
 }
 
-fn report(db: Db, prev: Option<Db>, webhook_url: &str) {
+/// Stage wins and podiums, in the order given.
+fn stage_wins_table(stage_wins: &[(String, StageWins)]) -> PreEscaped<String> {
+    html!(
+        @if !stage_wins.is_empty() {
+            table class="stage-wins" {
+                thead {
+                    th { "driver" }
+                    th { "wins" }
+                    th { "podiums" }
+                }
+                @for (name, wins) in stage_wins {
+                    tr {
+                        td { a href=(format!("/{}.html", url_safe(name))) { (name) } }
+                        td { (wins.wins) }
+                        td { (wins.podiums) }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// What changed since the previous snapshot, for each rally and stage.
+fn diff(db: &Db, prev: Option<&Db>, config: &Config) -> NotificationTable {
     let mut table: NotificationTable = Default::default();
 
     for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
         // Find corresponding previous rally by name
-        let prev_results = prev.as_ref().and_then(|prev| {
-            prev.rallys
-                .iter()
-                .zip(prev.results.iter())
-                .find_map(|(prev_rally, prev_results)| {
-                    prev_rally.title.eq(&rally.title).then_some(prev_results)
-                })
-        });
+        let prev_results = prev.and_then(|prev| prev.rally_results(&rally.title));
 
         // For each user, if they drove a new record, add it to the notification table
         for driver in &results.driver_results {
-            for (stage_idx, ((stage, _group, weather), stage_results)) in
-                rally.stages.iter().zip(driver.stages.iter()).enumerate()
+            for (stage_with_leaderboard, stage_results) in
+                rally.stages.iter().zip(driver.stages.iter())
             {
-                let stage_name = format!("{stage} {weather}");
+                let (stage, _group, weather) = stage_with_leaderboard;
+                let stage_name = format!("{} {weather}", StageName(stage));
                 let Some(stage_results) = stage_results else {
                     continue;
                 };
                 let (time, rank) = (stage_results.time_ms, stage_results.local_rank);
 
                 // Try to find the previous time
-                let prev_stage_result = prev_results
-                    .and_then(|r| {
-                        r.driver_results
-                            .iter()
-                            .find_map(|d| d.name.eq(&driver.name).then_some(&d.stages))
-                    })
-                    .and_then(|stages| stages[stage_idx].as_ref());
+                let prev_stage_result =
+                    prev_results.and_then(|r| r.stage_result(&driver.name, stage_with_leaderboard));
                 let prev_time = prev_stage_result.as_ref().map(|r| r.time_ms);
                 let prev_rank = prev_stage_result.as_ref().map(|r| r.local_rank);
-                let time_increased = prev_time.is_some_and(|prev_time| time < prev_time);
-                let rank_increased = prev_rank.is_some_and(|prev_rank| rank < prev_rank);
-                let rank_same = prev_rank.is_some_and(|prev_rank| rank == prev_rank);
-                let rank_decreased = prev_rank.is_some_and(|prev_rank| rank > prev_rank);
 
                 dbg!((
                     &driver.name,
@@ -353,104 +1134,115 @@ fn report(db: Db, prev: Option<Db>, webhook_url: &str) {
                         .or_default()
                         .push(row);
                 };
-                if prev_stage_result.is_none() {
-                    add_stage_row(Row::FirstTime { rank, name, time });
-                } else if time_increased && rank_increased {
-                    add_stage_row(Row::TimeImprovedRankIncreased {
+                match prev_stage_result {
+                    None => add_stage_row(Row::FirstTime { rank, name, time }),
+                    Some(prev) => add_stage_row(Row::from_change(
+                        stage_results.change_since(prev),
                         rank,
                         name,
                         time,
-                        prev: prev_time.unwrap(),
-                    });
-                } else if time_increased && rank_same {
-                    add_stage_row(Row::TimeImproved {
-                        rank,
-                        name,
-                        time,
-                        prev: prev_time.unwrap(),
-                    });
-                } else if time_increased && rank_decreased {
-                    add_stage_row(Row::TimeImprovedRankDecreased {
-                        rank,
-                        name,
-                        time,
-                        prev: prev_time.unwrap(),
-                    });
-                } else if rank_decreased {
-                    add_stage_row(Row::RankDecreased { rank, name, time });
-                } else {
-                    add_stage_row(Row::Unchanged {
-                        active: false,
-                        rank,
-                        name,
-                        time,
-                    })
+                        prev.time_ms,
+                        prev.local_rank,
+                        config.notify_threshold_ms,
+                    )),
                 }
             }
             let mut add_row = |row| table.entry(rally.title.clone()).or_default().0.push(row);
-            let prev_full_times = prev_results.map(|r| split_times(r).0);
+            let prev_full_times = prev_results.map(|r| split_times(r, config.tie_break).0);
             let prev_full_time = prev_full_times
                 .as_ref()
                 .and_then(|fts| fts.iter().find(|ft| ft.user_name == &driver.name));
-            let full_times = split_times(results).0;
+            let full_times = split_times(results, config.tie_break).0;
             let full_time = full_times.iter().find(|ft| ft.user_name == &driver.name);
             match (prev_full_time, full_time) {
-                (None, None) => {}
-                (Some(_), None) => panic!("user disappeared?"),
+                // A stage that failed to download this run leaves the driver
+                // without a full time, there's nothing to compare it to
+                (None, None) | (Some(_), None) => {}
                 (None, Some(ft)) => add_row(Row::FirstTime {
                     rank: ft.total_local_rank,
                     name: ft.user_name.to_string(),
                     time: ft.total_time,
                 }),
                 (Some(prev_ft), Some(ft)) => {
-                    let rank = ft.total_local_rank;
-                    let name = ft.user_name.to_string();
-                    let time = ft.total_time;
-                    let prev = prev_ft.total_time;
-                    let time_increased = time < prev_ft.total_time;
-                    let rank_increased = rank < prev_ft.total_local_rank;
-                    let rank_same = rank == prev_ft.total_local_rank;
-                    let rank_decreased = rank > prev_ft.total_local_rank;
-                    if time_increased && rank_increased {
-                        add_row(Row::TimeImprovedRankIncreased {
-                            rank,
-                            name,
-                            time,
-                            prev,
-                        });
-                    } else if time_increased && rank_same {
-                        add_row(Row::TimeImproved {
-                            rank,
-                            name,
-                            time,
-                            prev,
-                        });
-                    } else if time_increased && rank_decreased {
-                        add_row(Row::TimeImprovedRankDecreased {
-                            rank,
-                            name,
-                            time,
-                            prev,
-                        });
-                    } else if rank_decreased {
-                        add_row(Row::RankDecreased { rank, name, time });
-                    } else {
-                        add_row(Row::Unchanged {
-                            active: false,
-                            rank,
-                            name,
-                            time,
-                        })
-                    }
+                    let change = Change {
+                        time: ft.total_time.cmp(&prev_ft.total_time),
+                        local_rank: ft.total_local_rank.cmp(&prev_ft.total_local_rank),
+                        world_rank: Ordering::Equal,
+                    };
+                    add_row(Row::from_change(
+                        change,
+                        ft.total_local_rank,
+                        ft.user_name.to_string(),
+                        ft.total_time,
+                        prev_ft.total_time,
+                        prev_ft.total_local_rank,
+                        config.notify_threshold_ms,
+                    ));
+                }
+            }
+        }
+    }
+
+    // Rivals who swapped places since the previous run
+    for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+        let Some(prev_results) = prev.and_then(|prev| prev.rally_results(&rally.title)) else {
+            continue;
+        };
+        let full_times = split_times(results, config.tie_break).0;
+        let prev_full_times = split_times(prev_results, config.tie_break).0;
+        let total_rank = |full_times: &[FullTime], name: &str| {
+            full_times
+                .iter()
+                .find(|ft| ft.user_name == name)
+                .map(|ft| (ft.total_local_rank, ft.total_time))
+        };
+        let stage_rank = |results: &RallyResults, name: &str, stage: &StageWithLeaderboard| {
+            results
+                .stage_result(name, stage)
+                .map(|stage_result| (stage_result.local_rank, stage_result.time_ms))
+        };
+
+        for rivals in &config.rivalries {
+            if let Some(row) = overtake(
+                rivals,
+                rivals
+                    .each_ref()
+                    .map(|name| total_rank(&prev_full_times, name)),
+                rivals.each_ref().map(|name| total_rank(&full_times, name)),
+            ) {
+                table.entry(rally.title.clone()).or_default().0.push(row);
+            }
+            for stage_with_leaderboard in &rally.stages {
+                let (stage, _group, weather) = stage_with_leaderboard;
+                if let Some(row) = overtake(
+                    rivals,
+                    rivals
+                        .each_ref()
+                        .map(|name| stage_rank(prev_results, name, stage_with_leaderboard)),
+                    rivals
+                        .each_ref()
+                        .map(|name| stage_rank(results, name, stage_with_leaderboard)),
+                ) {
+                    table
+                        .entry(rally.title.clone())
+                        .or_default()
+                        .1
+                        .entry(format!("{} {weather}", StageName(stage)))
+                        .or_default()
+                        .push(row);
                 }
             }
         }
     }
 
     let sort_and_activate_rows = |rows: &mut Vec<Row>| {
-        rows.sort_by_key(Row::rank);
+        rows.sort_by(|row1, row2| {
+            row1.rank()
+                .cmp(&row2.rank())
+                .then_with(|| row1.name().cmp(row2.name()))
+        });
 
-        for i in 0..rows.len() - 1 {
+        for i in 0..rows.len().saturating_sub(1) {
             let (head, tail) = rows.split_at_mut(i + 1);
             if let Row::Unchanged { active, .. } = &mut head[i]
                 && !matches!(tail[0], Row::Unchanged { .. })
@@ -468,159 +1260,414 @@ fn report(db: Db, prev: Option<Db>, webhook_url: &str) {
         .flat_map(|(_, stages)| stages.values_mut())
         .for_each(sort_and_activate_rows);
 
-    let mut interval_parts =
-        vec![html!(div { "interval time | " a href="/absolute.html" { "absolute time" }})];
-    let mut absolute_parts =
-        vec![html!(div { a href = "/index.html" { "interval time" } " | absolute time" })];
-    let mut pages: BTreeMap<String, Vec<_>> = Default::default();
-
-    for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
-        let (full_times, partial_times) = split_times(results);
-        let (fastest_total, fastest_stages) = fastest_times(&full_times, results);
+    table
+}
 
-        interval_parts.push(html!(h2 { (rally.title) }));
-        // Total interval results table for each rally. (stages) x (drivers).
-        interval_parts.push(html!(
-            table class="rally" {
-                thead {
-                    th { "driver" }
-                    th { }
-                    th { "total" }
-                    @for (stage, _group, weather) in &rally.stages {
-                        th { a href=(format!("/{}.html", url_safe(&format!("{stage} {weather}")))) { (stage) " (" (weather) ")" } }
-                    }
-                }
-                @for ft in &full_times {
+/// The rows of a diff, like in the notification.
+fn diff_table(rows: &[Row]) -> PreEscaped<String> {
+    html!(
+        table class="diff" {
+            @for row in rows {
+                @if !matches!(row, Row::Unchanged { active: false, .. }) {
                     tr {
-                        td { a href=(format!("/{}.html", url_safe(ft.user_name))) { (ft.user_name) } }
-                        td { }
-                        @let total = ft.total_time;
-                        @let fastest_total = fastest_total.unwrap();
-                        @if total == fastest_total {
-                            td class="fastest" { (format_time(total, true)) }
+                        td { (row.marker()) }
+                        td { (row.rank()) }
+                        td { a href=(format!("/{}.html", url_safe(row.name()))) { (row.name()) } }
+                        td class="time" { (format_time(row.time(), false)) }
+                        @if let Some(prev) = row.prev() {
+                            td class="interval" { (format_delta(row.time(), prev, false)) }
                         } @else {
-                            td { (format_delta(total, fastest_total, true)) }
-                        }
-                        @for (i, time) in ft.stage_times.iter().copied().enumerate() {
-                            @let fast = fastest_stages[i].unwrap();
-                            @if time == fast {
-                                td class="fastest" { (format_time(time, false)) }
-                            } @else {
-                                td { (format_delta(time, fast, false)) }
-                            }
+                            td { }
                         }
                     }
                 }
-                @for pt in &partial_times {
+            }
+        }
+    )
+}
+
+/// Writes `public/{a}-vs-{b}.html`, comparing two drivers on every stage.
+fn head_to_head_page(db: &Db, a: &str, b: &str, config: &Config) {
+    let mut parts = Vec::new();
+    let mut wins = [0, 0];
+    for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+        let h2h = head_to_head(results, a, b);
+        wins[0] += h2h.wins[0];
+        wins[1] += h2h.wins[1];
+        parts.push(html!(
+            h2 { (rally.title) }
+            table class="head-to-head" {
+                thead {
+                    th { "stage" }
+                    th { (a) }
+                    th { (b) }
+                    th { "gap" }
+                }
+                @for ((stage, _group, weather), h2h_stage) in rally.stages.iter().zip(&h2h.stages) {
                     tr {
-                        td { a href=(format!("/{}.html", url_safe(pt.user_name))) { (pt.user_name) } }
-                        td { "*" }
-                        @let total = pt.total_time;
-                        td { (format_time(total, true)) }
-                        @for (i, time) in pt.stage_times.iter().copied().enumerate() {
-                            @if let Some(time) = time {
-                                @let fast = fastest_stages[i].unwrap();
-                                @if time == fast {
-                                    td class="fastest" { (format_time(time, false)) }
-                                } @else {
-                                    td { (format_delta(time, fast, false)) }
-                                }
-                            } @else {
-                                td { }
+                        td { (StageName(stage)) " (" (weather) ")" }
+                        @if let Some(h2h_stage) = h2h_stage {
+                            td class=[(h2h_stage.a_ms < h2h_stage.b_ms).then_some("fastest")] {
+                                (format_time_with(h2h_stage.a_ms, false, rally.precision))
+                            }
+                            td class=[(h2h_stage.b_ms < h2h_stage.a_ms).then_some("fastest")] {
+                                (format_time_with(h2h_stage.b_ms, false, rally.precision))
                             }
+                            td class="interval" {
+                                (format_signed_delta_with(h2h_stage.a_ms, h2h_stage.b_ms, false, rally.precision))
+                            }
+                        } @else {
+                            td { }
+                            td { }
+                            td { "incomparable" }
                         }
                     }
                 }
             }
         ));
+    }
+    parts.insert(0, html!(p { (a) " " (wins[0]) " – " (wins[1]) " " (b) }));
 
-        absolute_parts.push(html!(h2 { (rally.title) }));
-        // Total absolute results table for each rally. (stages) x (drivers).
-        absolute_parts.push(html!(
-            table class="rally" {
-                thead {
-                    th { "driver" }
-                    th { }
-                    th { "total" }
-                    @for (stage, _group, weather) in &rally.stages {
-                        th { a href=(format!("/{}.html", url_safe(&format!("{stage} {weather}")))) { (stage) " (" (weather) ")" } }
-                    }
+    write_atomic(
+        format!("public/{}-vs-{}.html", url_safe(a), url_safe(b)),
+        html_page(&format!("{a} vs {b}"), &config.font_stylesheets, &parts).into_string(),
+    )
+    .unwrap();
+}
+
+/// Write `public/compare.html` with what changed from snapshot `a` to `b`.
+fn compare(a: &Db, b: &Db, config: &Config) {
+    let table = diff(b, Some(a), config);
+
+    let mut parts = Vec::new();
+    for (rally, results) in b.rallys.iter().zip(b.results.iter()) {
+        parts.push(html!(h2 { (rally.title) }));
+        if let Some((total_rows, stages)) = table.get(&rally.title) {
+            if total_rows.iter().any(|row| !row.is_unchanged()) {
+                parts.push(html!(h3 { "total" } (diff_table(total_rows))));
+            }
+            for (stage, rows) in stages {
+                if rows.iter().any(|row| !row.is_unchanged()) {
+                    parts.push(html!(h3 { (stage) } (diff_table(rows))));
                 }
-                @for ft in &full_times {
-                    tr {
-                        td { a href=(format!("/{}.html", url_safe(&ft.user_name))) { (ft.user_name) } }
-                        td { }
-                        @let total = ft.total_time;
-                        @let fastest_total = fastest_total.unwrap();
-                        @if total == fastest_total {
-                            td class="fastest" { (format_time(total, true)) }
-                        } @else {
-                            td { (format_time(total, true)) }
-                        }
-                        @for (i, time) in ft.stage_times.iter().copied().enumerate() {
-                            @let fast = fastest_stages[i].unwrap();
-                            @if time == fast {
-                                td class="fastest" { (format_time(time, false)) }
-                            } @else {
-                                td { (format_time(time, false)) }
-                            }
-                        }
-                    }
+            }
+        }
+        parts.push(rally_table(
+            rally,
+            results,
+            config,
+            TableMode::Interval,
+            &BTreeSet::new(),
+        ));
+    }
+
+    write_atomic(
+        "public/compare.html",
+        html_page("comparison", &config.font_stylesheets, &parts).into_string(),
+    )
+    .unwrap();
+}
+
+/// The contents of `public/data.json`. It's built from the results rather
+/// than being the [`Db`] itself, so that it can stay the same when the
+/// snapshot format changes.
+#[derive(Serialize)]
+struct DataJson<'a> {
+    /// Bumped whenever the schema changes in a way that breaks readers.
+    schema_version: u32,
+    /// When the newest time was first seen, so that unchanged results give
+    /// an unchanged file.
+    generated_at: i64,
+    rallys: Vec<RallyJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct RallyJson<'a> {
+    title: &'a str,
+    /// Stage names, in the same order as each driver's `stages`.
+    stages: Vec<String>,
+    standings: Vec<StandingRow>,
+}
+
+/// How long each phase of a run took, printed with `--profile`.
+struct Profile {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+    // (index of the phase, name, duration)
+    parts: Vec<(usize, &'static str, Duration)>,
+    last: Instant,
+}
+
+impl Profile {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            phases: Vec::new(),
+            parts: Vec::new(),
+            last: Instant::now(),
+        }
+    }
+
+    /// End the phase `name`, which started when the previous phase ended.
+    fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.last));
+        self.last = now;
+    }
+
+    /// Like [`Profile::phase`], for a phase that timed its own `parts`. They're
+    /// listed under it, and don't count towards the total again.
+    fn phase_parts(
+        &mut self,
+        name: &'static str,
+        parts: impl IntoIterator<Item = (&'static str, Duration)>,
+    ) {
+        self.phase(name);
+        let phase = self.phases.len() - 1;
+        self.parts.extend(
+            parts
+                .into_iter()
+                .map(|(part, duration)| (phase, part, duration)),
+        );
+    }
+
+    fn print(&self) {
+        if !self.enabled {
+            return;
+        }
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        for (i, (name, duration)) in self.phases.iter().enumerate() {
+            eprintln!("{name:<12} {:>9.3}s", duration.as_secs_f64());
+            for (_, part, duration) in self.parts.iter().filter(|(phase, ..)| *phase == i) {
+                eprintln!("{part:<12} {:>9.3}s", duration.as_secs_f64());
+            }
+        }
+        eprintln!("{:<12} {:>9.3}s", "total", total.as_secs_f64());
+    }
+}
+
+fn report(
+    db: Db,
+    prev: Option<Db>,
+    snapshots: &[(i64, Db)],
+    records: &StageRecords,
+    bests: &PersonalBests,
+    inactive: &BTreeSet<String>,
+    world: &BTreeMap<String, WorldResults>,
+    config: &Config,
+    dry_run: bool,
+    profile: &mut Profile,
+) {
+    let table = diff(&db, prev.as_ref(), config);
+    profile.phase("diff");
+
+    // Pages are dated by when their times were first seen rather than by the
+    // clock, so the same results give the same bytes. Snapshots from before
+    // times were stamped have nothing else to go by.
+    let updated_at = db
+        .updated_at()
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let updated_at_date = chrono::DateTime::from_timestamp(updated_at, 0).unwrap_or_default();
+
+    let mut table_parts = TableMode::ALL.map(|mode| vec![mode.nav()]);
+    // { page => { rally title => parts } }, so the parts of a page are in the
+    // same order every run
+    let mut pages: BTreeMap<String, BTreeMap<&str, Vec<_>>> = Default::default();
+    // { page => when the newest time on it was first seen }, for the footer,
+    // so pages without new times come out the same as last run
+    let mut page_updated_at: BTreeMap<String, i64> = BTreeMap::new();
+    let mut page_updated = |page: &str, observed_at: Option<i64>| {
+        if let Some(observed_at) = observed_at {
+            let updated_at = page_updated_at.entry(page.to_string()).or_default();
+            *updated_at = (*updated_at).max(observed_at);
+        }
+    };
+    if config.whats_new_banner {
+        let banner = whats_new_banner(&table, updated_at);
+        table_parts
+            .iter_mut()
+            .for_each(|parts| parts.push(banner.clone()));
+    }
+
+    let conditions = html!(
+        table class="conditions" {
+            thead {
+                th { "weather" }
+                th { "stages" }
+            }
+            @for (weather, count) in weather_distribution(&db.rallys) {
+                tr {
+                    td { (weather) }
+                    td { (count) }
                 }
-                @for pt in &partial_times {
-                    tr {
-                        td { a href=(format!("/{}.html", url_safe(&pt.user_name))) { (pt.user_name) } }
-                        td { "*" }
-                        @let total = pt.total_time;
-                        td { (format_time(total, true)) }
-                        @for (i, time) in pt.stage_times.iter().copied().enumerate() {
-                            @if let Some(time) = time {
-                                @let fast = fastest_stages[i].unwrap();
-                                @if time == fast {
-                                    td class="fastest" { (format_time(time, false)) }
-                                } @else {
-                                    td { (format_time(time, false)) }
-                                }
-                            } @else {
-                                td { }
-                            }
-                        }
-                    }
+            }
+        }
+    );
+    table_parts
+        .iter_mut()
+        .for_each(|parts| parts.push(conditions.clone()));
+
+    // { name => stage wins over all rallys }
+    let mut all_stage_wins: BTreeMap<String, StageWins> = BTreeMap::new();
+    for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+        let (full_times, partial_times, _) = split_times(results, config.tie_break);
+        let (_, fastest_stages) = fastest_times(&full_times, results);
+        let precision = rally.precision;
+
+        for (mode, parts) in TableMode::ALL.into_iter().zip(&mut table_parts) {
+            parts.push(html!(h2 { (rally.title) }));
+            parts.push(rally_table(rally, results, config, mode, inactive));
+        }
+
+        let mut rally_parts = Vec::new();
+        if let Some(dotr) =
+            driver_of_the_rally(&full_times, &fastest_stages, config.driver_of_the_rally)
+        {
+            rally_parts.push(html!(
+                p class="driver-of-the-rally" {
+                    "driver of the rally: "
+                    a href=(format!("/{}.html", url_safe(dotr.user_name))) { (dotr.user_name) }
+                    (format!(
+                        " (total {:.2}, stage wins {:.2}, consistency {:.2})",
+                        dotr.total_rank, dotr.stage_wins, dotr.consistency
+                    ))
+                }
+            ));
+        }
+
+        if config.bogey_driver
+            && let Some(bogey) = bogey_driver(results)
+        {
+            rally_parts.push(html!(
+                p class="footnote" {
+                    "most last places: "
+                    a href=(format!("/{}.html", url_safe(&bogey))) { (bogey) }
                 }
+            ));
+        }
+
+        let rally_stage_wins = stage_wins(results);
+        for (name, wins) in &rally_stage_wins {
+            let all = all_stage_wins.entry(name.clone()).or_default();
+            all.wins += wins.wins;
+            all.podiums += wins.podiums;
+        }
+        rally_parts.push(stage_wins_table(&rally_stage_wins));
+
+        // Stages that nobody has driven yet
+        rally_parts.push(html!(
+            @for i in unattempted_stages(results) {
+                @let (stage, _group, weather) = &rally.stages[i];
+                p { "0/" (db.user_names.len()) " drivers have attempted " (StageName(stage)) " (" (weather) ")" }
             }
         ));
+        for parts in &mut table_parts {
+            parts.extend(rally_parts.iter().cloned());
+        }
 
         // For each driver, in-depth stats for each stage
+        let cars = car_stats(results);
         for driver in &results.driver_results {
-            pages.entry(driver.name.clone()).or_default().push(html!(
+            for stage_result in driver.stages.iter().flatten() {
+                page_updated(&driver.name, stage_result.observed_at);
+            }
+            // Leave out the world rank column if none of them downloaded
+            let has_world_ranks = driver
+                .stages
+                .iter()
+                .flatten()
+                .any(|stage_result| stage_result.world_rank.is_some());
+            pages
+                .entry(driver.name.clone())
+                .or_default()
+                .entry(&rally.title)
+                .or_default()
+                .push(html!(
                 h2 { (rally.title) }
                 table class="driver" {
                     thead {
                         th { "stage" }
                         th { "time" }
+                        th { "all-time best" }
+                        th { "progression" }
                         th { "interval" }
                         th { "car" }
                         th { "rank" }
-                        th { "world rank" }
+                        th { "percentile" }
+                        @if has_world_ranks {
+                            th { "world rank" }
+                        }
                     }
                     @for (i, ((stage, group, weather), stage_result)) in rally.stages.iter().zip(&driver.stages).enumerate() {
                         @let Some(stage_result) = stage_result else { continue; };
                         @let time = stage_result.time_ms;
+                        @let stage_times = results.stage_results[i].iter().map(|(_, r)| r.time_ms).collect_vec();
                         tr {
-                            td { a href=(format!("/{}.html", url_safe(&format!("{stage} {weather}")))) { (stage) " (" (weather) ")" } }
-                            td class="time" { (format_time(time, false)) }
+                            td { a href=(format!("/{}.html", url_safe(&stage_page(config, stage, *weather)))) { (StageName(stage)) " (" (weather) ")" } }
+                            td class="time" { (format_time_with(time, false, precision)) }
+                            @let best = bests.get(&rally.title, &rally.stages[i], &driver.name).unwrap_or(time);
+                            @if best < time {
+                                td class="time" {
+                                    (format_time_with(best, false, precision))
+                                    " (" (format_delta_with(time, best, false, precision)) ")"
+                                }
+                            } @else {
+                                td { span class="pb" { "PB" } }
+                            }
+                            @let times = progression(snapshots, &rally.title, &rally.stages[i], &driver.name);
+                            td class="progression" {
+                                (times.into_iter().map(|(_, time)| time).dedup().map(|time| format_time_with(time, false, precision)).join(" → "))
+                            }
                             @let fast = fastest_stages[i].unwrap();
                             @if time == fast {
                                 td class="interval" { "-:--.---" }
                             } @else {
-                                td class="interval" { (format_delta(time, fast, false)) }
+                                td class="interval" { (format_delta_with(time, fast, false, precision)) }
                             }
                             td { (car_name(*group, stage_result.car)) }
                             td { (stage_result.local_rank) }
-                            @if let Some(world_rank) = stage_result.world_rank {
-                                td { (world_rank) }
-                            } @else {
-                                td { }
+                            td { (format!("{:.0}%", percentile(time, &stage_times))) }
+                            @if has_world_ranks {
+                                @if let Some(world_rank) = stage_result.world_rank {
+                                    td { (world_rank) }
+                                } @else {
+                                    td { "—" }
+                                }
+                            }
+                        }
+                    }
+                }
+                @let remaining = remaining_stages(driver, &rally.stages);
+                @if remaining.is_empty() {
+                    p { span class="complete" { "complete!" } }
+                } @else {
+                    p {
+                        "stages left: "
+                        (remaining.iter().map(|(stage, _group, weather)| format!("{} ({weather})", StageName(stage))).join(", "))
+                    }
+                }
+                @if let Some(ft) = full_times.iter().find(|ft| ft.user_name == driver.name)
+                    && let Some(best) = bests.theoretical_best(&rally.title, &rally.stages, &driver.name)
+                    && best < ft.total_time
+                {
+                    p {
+                        "with your best times on every stage: "
+                        (format_time_with(best, true, precision))
+                        " (" (format_signed_delta_with(best, ft.total_time, true, precision)) ")"
+                    }
+                }
+                @if let Some(cars) = cars.get(&driver.name) {
+                    table class="cars" {
+                        thead {
+                            th { "car" }
+                            th { "stages" }
+                            th { "average interval" }
+                        }
+                        @for (car, stats) in cars.iter().sorted_by_key(|(_, stats)| std::cmp::Reverse(stats.stages.len())) {
+                            tr {
+                                td { (car) }
+                                td { (stats.stages.len()) }
+                                td class="interval" { (format_time_with(stats.average_delta_ms, false, precision)) }
                             }
                         }
                     }
@@ -629,24 +1676,61 @@ fn report(db: Db, prev: Option<Db>, webhook_url: &str) {
         }
 
         // For each stage, in-depth stats
+        let prev_results = prev
+            .as_ref()
+            .and_then(|prev| prev.rally_results(&rally.title));
         for (i, (stage, group, weather)) in rally.stages.iter().enumerate() {
-            let stage_name = &format!("{stage} {weather}");
+            let stage_name = &stage_page(config, stage, *weather);
             let Some(fast) = fastest_stages[i] else {
                 continue;
             };
+            if config.combine_stage_variants {
+                pages
+                    .entry(stage_name.clone())
+                    .or_default()
+                    .entry(&rally.title)
+                    .or_default()
+                    .push(html!(h3 { (StageName(stage)) " (" (weather) ")" }));
+            }
+            for (_, stage_result) in &results.stage_results[i] {
+                page_updated(stage_name, stage_result.observed_at);
+            }
             struct S {
                 name: String,
                 time: usize,
+                prev_time: Option<usize>,
                 car: usize,
                 world_rank: Option<usize>,
+                world_entries: Option<usize>,
+                observed_at: Option<i64>,
             }
+            let stage_result = |name: &str| {
+                results
+                    .driver_results
+                    .iter()
+                    .find(|driver| driver.name == name)?
+                    .stages
+                    .get(i)?
+                    .as_ref()
+            };
+            let observed_at = |name: &str| stage_result(name)?.observed_at;
+            let world_entries = |name: &str| stage_result(name)?.world_entries;
+            // The driver's time on this stage in the previous run
+            let prev_time = |name: &str| {
+                prev_results?
+                    .stage_result(name, &rally.stages[i])
+                    .map(|stage_result| stage_result.time_ms)
+            };
             let times = full_times
                 .iter()
                 .map(|ft| S {
                     name: ft.user_name.to_string(),
                     time: ft.stage_times[i],
+                    prev_time: prev_time(ft.user_name),
                     car: ft.cars[i],
                     world_rank: ft.world_rank[i],
+                    world_entries: world_entries(ft.user_name),
+                    observed_at: observed_at(ft.user_name),
                 })
                 .chain(partial_times.iter().filter_map(|pt| {
                     let time = pt.stage_times[i]?;
@@ -654,105 +1738,1154 @@ fn report(db: Db, prev: Option<Db>, webhook_url: &str) {
                     Some(S {
                         name: pt.user_name.to_string(),
                         time,
+                        prev_time: prev_time(pt.user_name),
                         car,
                         world_rank: pt.world_rank.get(i).copied().flatten(),
+                        // Partial times don't get a world percentile
+                        world_entries: None,
+                        observed_at: observed_at(pt.user_name),
                     })
                 }))
-                .sorted_by_key(|time| time.time);
-            pages.entry(stage_name.clone()).or_default().push(html!(
+                .sorted_by(|time1, time2| {
+                    let by_world_rank = match config.stage_sort {
+                        StageSort::Time => Ordering::Equal,
+                        // `None` would sort first on its own
+                        StageSort::WorldRank => (time1.world_rank.is_none(), time1.world_rank)
+                            .cmp(&(time2.world_rank.is_none(), time2.world_rank)),
+                    };
+                    by_world_rank
+                        .then_with(|| time1.time.cmp(&time2.time))
+                        .then_with(|| time1.name.cmp(&time2.name))
+                })
+                .collect_vec();
+            let stage_times = times.iter().map(|time| time.time).collect_vec();
+            // A date rather than a number of days, which would change every day
+            if let Some(set_at) = records
+                .set_at(&rally.title, &rally.stages[i])
+                .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+            {
+                pages
+                    .entry(stage_name.clone())
+                    .or_default()
+                    .entry(&rally.title)
+                    .or_default()
+                    .push(html!(p { "this record has stood since " (set_at.date_naive()) }));
+            }
+            let has_world_ranks = times.iter().any(|time| time.world_rank.is_some());
+            pages
+                .entry(stage_name.clone())
+                .or_default()
+                .entry(&rally.title)
+                .or_default()
+                .push(html!(
                 table class="stage" {
                     thead {
                         th { "driver" }
                         th { "time" }
                         th { "interval" }
+                        @if config.improvement_column {
+                            th { "since last run" }
+                        }
                         th { "car" }
-                        th { "world rank" }
+                        th { "percentile" }
+                        @if has_world_ranks {
+                            th { "world rank" }
+                        }
                     }
                     @for time in times {
-                        tr {
+                        tr.focus[config.is_focus(&time.name)] {
                             td { a href=(format!("/{}.html", url_safe(&time.name))) { (time.name) } }
-                            td class="time" { (format_time(time.time, false)) }
+                            td class="time" { (format_time_with(time.time, false, precision)) (set_on(config, time.observed_at)) }
                             @if time.time == fast {
                                 td class="interval" { "-:--.---" }
                             } @else {
-                                td class="interval" { (format_delta(time.time, fast, false)) }
+                                td class="interval" { (format_delta_with(time.time, fast, false, precision)) }
+                            }
+                            @if config.improvement_column {
+                                @if let Some(prev_time) = time.prev_time {
+                                    @if time.time < prev_time {
+                                        td class="interval improved" { (format_signed_delta_with(time.time, prev_time, false, precision)) }
+                                    } @else {
+                                        td class="interval" { (format_signed_delta_with(time.time, prev_time, false, precision)) }
+                                    }
+                                } @else {
+                                    td { }
+                                }
                             }
                             td { (car_name(*group, time.car)) }
-                            @if let Some(world_rank) = time.world_rank {
-                                td { (world_rank) }
-                            } @else {
-                                td { }
+                            td { (format!("{:.0}%", percentile(time.time, &stage_times))) }
+                            @if has_world_ranks {
+                                @if let Some(world_rank) = time.world_rank {
+                                    td {
+                                        (world_rank)
+                                        @if let Some(world_entries) = time.world_entries {
+                                            " (top " (format!("{:.1}", world_top_percent(world_rank, world_entries))) "%)"
+                                        }
+                                    }
+                                } @else {
+                                    td { "—" }
+                                }
                             }
                         }
                     }
                 }
             ));
+
+            if let Some(world_times) = world
+                .get(&rally.title)
+                .and_then(|stages| stages.get(i))
+                .filter(|world_times| !world_times.is_empty())
+            {
+                let world_fast = world_times[0].1.time_ms;
+                pages
+                    .entry(stage_name.clone())
+                    .or_default()
+                    .entry(&rally.title)
+                    .or_default()
+                    .push(html!(
+                    h3 { "world top " (world_times.len()) }
+                    table class="stage world" {
+                        thead {
+                            th { "world rank" }
+                            th { "driver" }
+                            th { "time" }
+                            th { "interval" }
+                            th { "car" }
+                        }
+                        @for (name, world_time) in world_times {
+                            @let friend = results.driver_results.iter().any(|driver| &driver.name == name);
+                            tr.friend[friend].focus[config.is_focus(name)] {
+                                td { (world_time.local_rank) }
+                                td { (name) }
+                                td class="time" { (format_time_with(world_time.time_ms, false, precision)) }
+                                td class="interval" { (format_delta_with(world_time.time_ms, world_fast, false, precision)) }
+                                td { (car_name(*group, world_time.car)) }
+                            }
+                        }
+                    }
+                ));
+            }
         }
     }
 
+    profile.phase("html");
+
     dbg!(&table);
 
+    // The notification, for those who aren't in the Discord
+    let recap = match notification_message(&table) {
+        Some(message) => html!(pre { (message) }),
+        None => html!(p { "nothing changed since the last run" }),
+    };
+    write_if_changed(
+        "public/recap.html",
+        html_page_updated_at("recap", &config.font_stylesheets, [&recap], updated_at_date)
+            .into_string(),
+    )
+    .unwrap();
+
     if prev.is_some() {
-        send_notification(&table, webhook_url);
+        route_notifications(&table, config, dry_run);
     }
+    profile.phase("notification");
 
-    std::fs::write(
-        "public/index.html",
-        html_page("basvektorernas art of rally-leaderboard", &interval_parts).into_string(),
-    )
-    .unwrap();
-    std::fs::write(
-        "public/absolute.html",
-        html_page("basvektorernas art of rally-leaderboard", &absolute_parts).into_string(),
-    )
-    .unwrap();
-    for (user, parts) in &pages {
-        std::fs::write(
-            format!("public/{}.html", url_safe(user)),
-            html_page(user, parts).into_string(),
+    if !inactive.is_empty() {
+        let alumni = html!(
+            p { "drivers who haven't set a new time in a while" }
+            ul {
+                @for name in inactive {
+                    li { a href=(format!("/{}.html", url_safe(name))) { (name) } }
+                }
+            }
+        );
+        write_if_changed(
+            "public/alumni.html",
+            html_page_updated_at(
+                "alumni",
+                &config.font_stylesheets,
+                [&alumni],
+                updated_at_date,
+            )
+            .into_string(),
+        )
+        .unwrap();
+    }
+
+    let all_stage_wins = all_stage_wins
+        .into_iter()
+        .sorted_by_key(|(_, wins)| std::cmp::Reverse((wins.wins, wins.podiums)))
+        .collect_vec();
+    let all_stage_wins = html!(
+        h2 { "stage wins, all rallys" }
+        (stage_wins_table(&all_stage_wins))
+    );
+    for parts in &mut table_parts {
+        parts.push(all_stage_wins.clone());
+    }
+
+    for (mode, parts) in TableMode::ALL.into_iter().zip(&table_parts) {
+        write_if_changed(
+            format!("public/{}", mode.page()),
+            html_page_updated_at(
+                "basvektorernas art of rally-leaderboard",
+                &config.font_stylesheets,
+                parts,
+                updated_at_date,
+            )
+            .into_string(),
+        )
+        .unwrap();
+    }
+    for (page, parts) in &pages {
+        let updated_at = page_updated_at
+            .get(page)
+            .and_then(|ts| chrono::DateTime::from_timestamp(*ts, 0))
+            .unwrap_or(updated_at_date);
+        write_if_changed(
+            format!("public/{}.html", url_safe(page)),
+            html_page_updated_at(
+                page,
+                &config.font_stylesheets,
+                parts.values().flatten(),
+                updated_at,
+            )
+            .into_string(),
         )
         .unwrap();
     }
+    let data = DataJson {
+        schema_version: 1,
+        generated_at: updated_at,
+        rallys: db
+            .rallys
+            .iter()
+            .zip(db.results.iter())
+            .map(|(rally, results)| RallyJson {
+                title: &rally.title,
+                stages: rally
+                    .stages
+                    .iter()
+                    .map(|(stage, _group, weather)| format!("{} ({weather})", StageName(stage)))
+                    .collect(),
+                standings: results.to_standings_table(config.tie_break),
+            })
+            .collect(),
+    };
+    write_if_changed(
+        "public/data.json",
+        serde_json::to_string_pretty(&data).unwrap(),
+    )
+    .unwrap();
+    for (rally, results) in db.rallys.iter().zip(db.results.iter()) {
+        let mut csv = Vec::new();
+        results.to_csv(&mut csv).unwrap();
+        write_if_changed(format!("public/{}.csv", url_safe(&rally.title)), csv).unwrap();
+    }
+    profile.phase("write");
+}
+
+/// Check the config and that the API is reachable, without writing anything.
+fn validate_only(config: &Config) -> Result<(), Whatever> {
+    let mut failed = false;
+    let mut report_check = |name: &str, result: Result<String, Whatever>| match result {
+        Ok(msg) => println!("ok    {name}: {msg}"),
+        Err(e) => {
+            println!("FAIL  {name}: {e}");
+            failed = true;
+        }
+    };
+
+    report_check(
+        "config",
+        config.validate().map(|_| "config is valid".to_string()),
+    );
+
+    let rallys = match config.rallys() {
+        Ok(rallys) => {
+            report_check("rallys", Ok(format!("built {} rallys", rallys.len())));
+            rallys
+        }
+        Err(e) => {
+            report_check("rallys", Err(e));
+            Vec::new()
+        }
+    };
+
+    let (user_ids, _) = config.users();
+    let urls = rallys
+        .iter()
+        .map(|rally| {
+            let leaderboards = rally
+                .stages
+                .iter()
+                .copied()
+                .map(|stage| (stage, config.platform))
+                .collect_vec();
+            friends_urls(&leaderboards, &user_ids)
+        })
+        .flatten_ok()
+        .collect::<Result<Vec<_>, Whatever>>();
+    let urls = match urls {
+        Ok(urls) => urls,
+        Err(e) => {
+            println!("FAIL  urls: {e}");
+            whatever!("Validation failed");
+        }
+    };
+    report_check("urls", Ok(format!("built {} leaderboard URLs", urls.len())));
+
+    // Asking the API with a broken config only tells us the config is broken
+    if failed {
+        println!("skip  api: fix the checks above first");
+        whatever!("Validation failed");
+    }
+    let api = (|| -> Result<String, Whatever> {
+        let Some(url) = urls.first() else {
+            whatever!("No rallys configured");
+        };
+        check_api(url, user_ids.len())
+    })();
+    report_check("api", api);
+
+    if failed {
+        whatever!("Validation failed");
+    }
+    Ok(())
+}
+
+/// Download the friends leaderboard at `url` to check that the API is
+/// reachable and answers with a leaderboard.
+fn check_api(url: &str, users: usize) -> Result<String, Whatever> {
+    let response: Response = ureq::get(url)
+        .call()
+        .with_whatever_context(|e| format!("Couldn't reach the API\n{e}"))?
+        .body_mut()
+        .read_json()
+        .with_whatever_context(|e| format!("Couldn't parse the API response\n{e}"))?;
+    Ok(format!(
+        "API is reachable, {} of {users} users have a time on the first stage",
+        response.leaderboard.len(),
+    ))
+}
+
+/// The value after `flag` in the command line arguments, like `--prev <path>`.
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .skip_while(|arg| *arg != flag)
+        .nth(1)
+        .map(String::as_str)
 }
 
 fn main() {
+    // Warnings from the library are logged with `tracing`
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_writer(std::io::stderr)
+        .init();
+    let mut profile = Profile::new(std::env::args().any(|arg| arg == "--profile"));
     let res = (|| -> Result<(), Whatever> {
         let config_str = std::fs::read_to_string("art-of-rally.toml")
             .with_whatever_context(|e| format!("Couldn't open config file\n{e}"))?;
-        let config: Config = toml::from_str(&config_str)
+        let mut config: Config = toml::from_str(&config_str)
             .with_whatever_context(|e| format!("Couldn't parse config file\n{e}"))?;
+        config.load_users_file()?;
+        profile.phase("config");
+
+        let args = std::env::args().collect_vec();
+        if let Some(platform) = arg_value(&args, "--platform") {
+            config.platform = platform.parse::<PlatformName>()?.0;
+        }
+        if let [_, command, a, b] = args.as_slice()
+            && command == "compare"
+        {
+            compare(&read_db(a)?, &read_db(b)?, &config);
+            return Ok(());
+        }
+        if let [_, command, a, b] = args.as_slice()
+            && command == "head-to-head"
+        {
+            let Some(db) = latest_snapshot("data")? else {
+                whatever!("No snapshots in data/ to compare drivers in");
+            };
+            head_to_head_page(&db, a, b, &config);
+            return Ok(());
+        }
+
+        if std::env::args().any(|arg| arg == "--validate-only") {
+            return validate_only(&config);
+        }
+        config.validate()?;
+        let rallys = config.rallys()?;
 
         std::fs::create_dir_all("data").unwrap();
 
-        let rallys = get_default_rallys();
-        let (user_ids, user_names) = config.users();
+        // Replaying a snapshot with `--current` never notifies, the changes
+        // were already sent when it was downloaded
+        let dry_run = args.iter().any(|arg| arg == "--dry-run")
+            || std::env::var("AOR_UTILS_DRY_RUN").ok() == Some("1".to_string())
+            || arg_value(&args, "--current").is_some();
 
-        let db = download(rallys, config.platform, user_ids, user_names)?;
-        let ts = chrono::Utc::now().timestamp();
+        // `--prev <path>` diffs against that snapshot instead of the newest one
+        let mut prev = match arg_value(&args, "--prev") {
+            Some(path) => Some(read_db(path)?),
+            None => latest_snapshot("data")?,
+        };
+        if let Some(prev) = &mut prev {
+            prev.apply_aliases(&config.aliases);
+        }
+        profile.phase("previous");
 
-        let prev = std::fs::read_dir("data")
-            .unwrap()
-            .filter_map(Result::ok)
-            .map(|entry| entry.path())
-            .sorted()
-            .last()
-            .map(|path| ron::from_str(&std::fs::read_to_string(path).unwrap()).unwrap());
+        // `--current <path>` replays a snapshot instead of downloading a new one
+        let (mut db, world) = match arg_value(&args, "--current") {
+            Some(path) => {
+                let db = read_db(path)?;
+                profile.phase("replay");
+                (db, BTreeMap::new())
+            }
+            None => {
+                let (user_ids, user_names) = config.users();
+                let (mut db, world, times) = download(
+                    rallys,
+                    config.platform,
+                    user_ids,
+                    user_names,
+                    prev.as_ref(),
+                    config.ms_per_score,
+                    config.world_top,
+                )?;
+                profile.phase_parts(
+                    "download",
+                    [
+                        ("  entries", times.entries),
+                        ("  ranks", times.ranks),
+                        ("  world", times.world),
+                        ("  assembly", times.assembly),
+                    ],
+                );
+                let ts = chrono::Utc::now().timestamp();
 
-        std::fs::write(format!("data/{ts}.ron"), ron::to_string(&db).unwrap()).unwrap();
+                for (rally, results) in db.rallys.iter().zip(db.results.iter_mut()) {
+                    let prev_results = prev
+                        .as_ref()
+                        .and_then(|prev| prev.rally_results(&rally.title));
+                    results.stamp_observed_at(prev_results, ts);
+                }
 
-        // let db =
-        //     ron::from_str(&std::fs::read_to_string(std::env::args().nth(2).unwrap()).unwrap()).unwrap();
-        // let prev = Some(
-        //     ron::from_str(&std::fs::read_to_string(std::env::args().nth(1).unwrap()).unwrap()).unwrap(),
-        // );
+                write_atomic(format!("data/{ts}.ron"), ron::to_string(&db).unwrap()).unwrap();
+                profile.phase("snapshot");
+                (db, world)
+            }
+        };
 
-        report(db, prev, &config.webhook_url);
+        db.apply_aliases(&config.aliases);
+        let mut snapshots = load_snapshots("data")?;
+        for (_, snapshot) in &mut snapshots {
+            snapshot.apply_aliases(&config.aliases);
+        }
+        let records = StageRecords::new(&snapshots);
+        let bests = PersonalBests::new(&snapshots);
+        profile.phase("history");
+        let inactive = config
+            .hide_inactive_after
+            .map(|runs| inactive_drivers(&snapshots, runs))
+            .unwrap_or_default();
+        report(
+            db,
+            prev,
+            &snapshots,
+            &records,
+            &bests,
+            &inactive,
+            &world,
+            &config,
+            dry_run,
+            &mut profile,
+        );
 
         Ok(())
     })();
 
+    profile.print();
     match res {
         Ok(_) => {}
         Err(e) => eprintln!("{e}"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use art_of_rally_leaderboard_api::{Area, Direction, Group, Weather};
+    use art_of_rally_leaderboard_utils::config::{RallyConfig, User};
+    use art_of_rally_leaderboard_utils::test_utils::{self, rally_results};
+
+    use super::*;
+
+    /// A config with `extra` appended, and without any users unless `extra`
+    /// has some.
+    fn config(extra: &str) -> Config {
+        let platform = "steam".parse::<PlatformName>().unwrap().0;
+        toml::from_str(&format!(
+            "platform = {}\nwebhook_url = \"https://example.com\"\nusers = []\n{extra}",
+            serde_json::to_string(&platform).unwrap()
+        ))
+        .unwrap()
+    }
+
+    /// A snapshot of one rally, "test", on stages 1, 2, ... with each
+    /// driver's time on each stage, ranked by time.
+    fn db(drivers: &[(&str, &[Option<usize>])]) -> Db {
+        test_utils::db("test", rally_results(drivers))
+    }
+
+    #[test]
+    fn diff_survives_a_stage_missing_this_run() {
+        let config = config("");
+        let prev = db(&[
+            ("alice", &[Some(61_000), Some(62_000)]),
+            ("bob", &[Some(63_000), Some(64_000)]),
+        ]);
+        // Stage 2 failed to download, so nobody has a time on it
+        let current = db(&[
+            ("alice", &[Some(60_000), None]),
+            ("bob", &[Some(63_000), None]),
+        ]);
+
+        let table = diff(&current, Some(&prev), &config);
+        let (totals, stages) = &table["test"];
+        assert!(totals.is_empty());
+        assert_eq!(stages.len(), 1);
+        assert!(notification_message(&table).unwrap().contains("alice"));
+
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config,
+            TableMode::Interval,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        assert!(html.contains("alice"));
+        assert!(html.contains("bob"));
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_split() {
+        let long_line = format!("{}\n", "å".repeat(15));
+        let sections = ["short\n".to_string(), format!("header\n{long_line}")];
+        let chunks = chunk_sections(&sections, 12);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 12), "{chunks:?}");
+        assert_eq!(chunks.concat(), sections.concat());
+    }
+
+    #[test]
+    fn fence_puts_the_message_on_its_own_line() {
+        let message = fence("biggest improvement: alice on Kenya 1\n");
+        assert_eq!(message.lines().next(), Some("```"));
+        assert!(message.ends_with("\n```"));
+    }
+
+    /// Tests that write to `public/` and `data/` take turns in a directory of
+    /// their own, since the working directory is shared by the whole process.
+    fn in_temp_dir<T>(name: &str, f: impl FnOnce() -> T) -> T {
+        static CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _cwd = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("aor-utils-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("public")).unwrap();
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        std::env::set_current_dir(cwd).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        result.unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    /// The files in `public/` by name.
+    fn public_files() -> BTreeMap<String, String> {
+        std::fs::read_dir("public")
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let contents = std::fs::read_to_string(entry.path()).unwrap();
+                (entry.file_name().to_string_lossy().into_owned(), contents)
+            })
+            .collect()
+    }
+
+    fn clone_db(db: &Db) -> Db {
+        ron::from_str(&ron::to_string(db).unwrap()).unwrap()
+    }
+
+    /// Run [`report`] on `current` with `prev` as the previous run and return
+    /// what it wrote. The times are stamped the way `main` stamps them, with
+    /// `prev` seen on 2023-11-14 and `current` a day later.
+    fn report_files(
+        current: &Db,
+        prev: Option<&Db>,
+        config: &Config,
+        profile: bool,
+    ) -> BTreeMap<String, String> {
+        let prev = prev.map(|prev| {
+            let mut prev = clone_db(prev);
+            for results in &mut prev.results {
+                results.stamp_observed_at(None, 1_700_000_000);
+            }
+            prev
+        });
+        let mut current = clone_db(current);
+        for (rally, results) in current.rallys.iter().zip(current.results.iter_mut()) {
+            let prev_results = prev
+                .as_ref()
+                .and_then(|prev| prev.rally_results(&rally.title));
+            results.stamp_observed_at(prev_results, 1_700_086_400);
+        }
+        let snapshots = prev
+            .iter()
+            .chain([&current])
+            .enumerate()
+            .map(|(ts, db)| (ts as i64, clone_db(db)))
+            .collect_vec();
+        let records = StageRecords::new(&snapshots);
+        let bests = PersonalBests::new(&snapshots);
+        report(
+            current,
+            prev,
+            &snapshots,
+            &records,
+            &bests,
+            &BTreeSet::new(),
+            &BTreeMap::new(),
+            config,
+            true,
+            &mut Profile::new(profile),
+        );
+        public_files()
+    }
+
+    #[test]
+    fn profiling_doesnt_change_the_report() {
+        let config = config("");
+        let prev = db(&[("alice", &[Some(61_000), Some(62_000)])]);
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(62_000)]),
+            ("bob", &[Some(63_000), None]),
+        ]);
+        let (with, without) = in_temp_dir("profile", || {
+            let with = report_files(&current, Some(&prev), &config, true);
+            let without = report_files(&current, Some(&prev), &config, false);
+            (with, without)
+        });
+        assert!(with.contains_key("index.html"));
+        assert_eq!(with, without);
+    }
+
+    #[test]
+    fn compact_notification_sums_up_each_driver() {
+        let prev = db(&[
+            ("alice", &[Some(61_000), Some(62_000)]),
+            ("bob", &[Some(60_000), Some(62_500)]),
+        ]);
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(60_700)]),
+            ("bob", &[Some(60_000), Some(62_500)]),
+            ("carol", &[Some(70_000), None]),
+        ]);
+        let table = diff(&current, Some(&prev), &config(""));
+
+        let sections = compact_notification_sections(&table);
+        let section = sections.last().unwrap();
+        let lines = section.lines().map(str::trim).collect_vec();
+        assert_eq!(
+            lines,
+            [
+                "",
+                "test",
+                "^ alice  -2.300 on 2 stages, +1 rank",
+                "v bob    -1 rank",
+                "> carol  1 new stage",
+            ]
+        );
+    }
+
+    /// Serve `responses` as (status, body), one per request, and return the
+    /// server's URL.
+    fn serve(responses: Vec<(u16, &'static str)>) -> String {
+        use std::io::{BufRead as _, Write as _};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while request.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn check_api_reports_unreachable_and_broken_apis() {
+        let url = serve(vec![(404, "")]);
+        let e = check_api(&url, 2).unwrap_err();
+        assert!(e.to_string().starts_with("Couldn't reach the API"), "{e}");
+
+        let url = serve(vec![(200, "<html>maintenance</html>")]);
+        let e = check_api(&url, 2).unwrap_err();
+        assert!(
+            e.to_string().starts_with("Couldn't parse the API response"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn validate_only_fails_on_a_bad_config() {
+        let config = config("");
+        assert!(validate_only(&config).is_err());
+    }
+
+    #[test]
+    fn validate_only_fails_on_rallys_that_dont_build() {
+        let mut config = config("");
+        config.users.push(User {
+            id: 1,
+            name: "alice".to_string(),
+        });
+        let rally = |title: &str| RallyConfig {
+            title: title.to_string(),
+            area: Area::Kenya,
+            group: Group::GroupB,
+            weather: Weather::Dry,
+            direction: Direction::Forward,
+            directions: Vec::new(),
+            weathers: Vec::new(),
+            stages: vec![1],
+            precision: Default::default(),
+        };
+        config.rallys = Some(vec![rally("kenya"), rally("kenya")]);
+        assert!(config.validate().is_ok());
+        assert!(validate_only(&config).is_err());
+    }
+
+    #[test]
+    fn partial_totals_are_compared_to_partials_with_as_many_stages() {
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(60_000)]),
+            ("bob", &[Some(60_500), Some(60_500)]),
+            ("carol", &[Some(60_500), None]),
+            ("dave", &[Some(61_000), None]),
+        ]);
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config(""),
+            TableMode::Interval,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        // Full times against the fastest full time
+        assert!(html.contains("02:00.000"));
+        assert!(html.contains("+00:01.000"));
+        // Partial times against the fastest partial time with one stage
+        assert!(html.contains("01:00.500"));
+        assert!(html.contains("+00:00.500"));
+    }
+
+    #[test]
+    fn improvement_column_shows_who_improved() {
+        let config = config("improvement_column = true");
+        let prev = db(&[("alice", &[Some(61_000)]), ("bob", &[Some(62_000)])]);
+        let current = db(&[("alice", &[Some(60_000)]), ("bob", &[Some(62_000)])]);
+        let files = in_temp_dir("improvement-column", || {
+            report_files(&current, Some(&prev), &config, false)
+        });
+
+        let (stage, _group, weather) = &current.rallys[0].stages[0];
+        let page = &files[&format!("{}.html", url_safe(&stage_page(&config, stage, *weather)))];
+        assert!(page.contains("since last run"));
+        assert_eq!(page.matches("interval improved").count(), 1);
+        assert!(page.contains("-0:01.000"));
+    }
+
+    #[test]
+    fn hidden_drivers_dont_set_the_fastest_stage_times() {
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(62_000)]),
+            ("bob", &[Some(59_000), Some(70_000)]),
+        ]);
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config(""),
+            TableMode::Combined,
+            &BTreeSet::from(["bob".to_string()]),
+        )
+        .into_string();
+        assert!(!html.contains("bob"), "{html}");
+        assert!(
+            html.contains(r#"<td class="fastest">1:00.000</td>"#),
+            "{html}"
+        );
+        assert!(!html.contains("pace-"), "{html}");
+    }
+
+    #[test]
+    fn combined_table_shows_times_and_gaps() {
+        let current = db(&[
+            ("alice", &[Some(60_000)]),
+            ("bob", &[Some(63_000)]),
+            ("carol", &[Some(66_000)]),
+        ]);
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config(""),
+            TableMode::Combined,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        for cell in [
+            r#"<td class="fastest">1:00.000</td>"#,
+            r#"<td class="fastest">01:00.000</td>"#,
+            r#"<td class="pace-1">1:03.000 (+5.00%)</td>"#,
+            r#"<td class="pace-1">01:03.000 (+5.00%)</td>"#,
+            r#"<td class="pace-2">1:06.000 (+10.00%)</td>"#,
+            r#"<td class="pace-2">01:06.000 (+10.00%)</td>"#,
+        ] {
+            assert!(html.contains(cell), "{cell} in {html}");
+        }
+    }
+
+    #[test]
+    fn compare_renders_what_changed_between_two_snapshots() {
+        let a = db(&[("alice", &[Some(61_000)]), ("bob", &[Some(63_000)])]);
+        let b = db(&[
+            ("alice", &[Some(60_000)]),
+            ("bob", &[Some(63_000)]),
+            ("carol", &[Some(65_000)]),
+        ]);
+        let files = in_temp_dir("compare", || {
+            compare(&a, &b, &config(""));
+            assert_eq!(std::fs::read_dir("data").unwrap().count(), 0);
+            public_files()
+        });
+        assert_eq!(files.keys().collect_vec(), ["compare.html"]);
+        let page = &files["compare.html"];
+        assert!(page.contains("<h2>test</h2>"), "{page}");
+        // alice improved by a second, carol is new and bob didn't change
+        assert!(page.contains("<td>~</td>"), "{page}");
+        assert!(page.contains("-0:01.000"), "{page}");
+        assert!(page.contains("<td>&gt;</td>"), "{page}");
+        assert!(!page.contains("<td>v</td>"), "{page}");
+    }
+
+    #[test]
+    fn rally_table_with_only_partial_finishers() {
+        let current = db(&[
+            ("alice", &[Some(60_000), None]),
+            ("bob", &[None, Some(70_000)]),
+        ]);
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config(""),
+            TableMode::Interval,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        assert!(html.contains("alice"));
+        assert!(html.contains("bob"));
+    }
+
+    #[test]
+    fn rivals_swapping_places_is_an_overtake() {
+        let rivals = ["alice".to_string(), "bob".to_string()];
+        let row = overtake(
+            &rivals,
+            [Some((2, 62_000)), Some((1, 61_000))],
+            [Some((1, 60_000)), Some((2, 61_000))],
+        );
+        assert!(matches!(
+            row,
+            Some(Row::Overtook { rank: 1, ref name, time: 60_000, ref rival })
+                if name == "alice" && rival == "bob"
+        ));
+
+        // Both improved, but kept their order
+        assert!(
+            overtake(
+                &rivals,
+                [Some((2, 62_000)), Some((1, 61_000))],
+                [Some((2, 60_500)), Some((1, 60_000))],
+            )
+            .is_none()
+        );
+        // bob has no previous time to have been overtaken on
+        assert!(
+            overtake(
+                &rivals,
+                [Some((1, 62_000)), None],
+                [Some((2, 62_000)), Some((1, 61_000))],
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn diff_reports_rivals_swapping_places() {
+        let config = config(r#"rivalries = [["alice", "bob"]]"#);
+        let prev = db(&[("alice", &[Some(62_000)]), ("bob", &[Some(61_000)])]);
+        let overtook = |current: &Db| {
+            let table = diff(current, Some(&prev), &config);
+            table.get("test").map_or(0, |(totals, stages)| {
+                totals
+                    .iter()
+                    .chain(stages.values().flatten())
+                    .filter(|row| matches!(row, Row::Overtook { .. }))
+                    .count()
+            })
+        };
+        // On the total and on the stage
+        let swapped = db(&[("alice", &[Some(60_000)]), ("bob", &[Some(61_000)])]);
+        assert_eq!(overtook(&swapped), 2);
+        let kept = db(&[("alice", &[Some(61_500)]), ("bob", &[Some(61_000)])]);
+        assert_eq!(overtook(&kept), 0);
+    }
+
+    #[test]
+    fn recap_page_has_the_notification() {
+        let config = config("");
+        let prev = db(&[("alice", &[Some(61_000)]), ("bob", &[Some(60_000)])]);
+        let current = db(&[
+            ("alice", &[Some(59_000)]),
+            ("bob", &[Some(60_000)]),
+            ("carol", &[Some(65_000)]),
+        ]);
+        let message = notification_message(&diff(&current, Some(&prev), &config)).unwrap();
+        let files = in_temp_dir("recap", || {
+            report_files(&current, Some(&prev), &config, false)
+        });
+        let escaped = message
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;");
+        let recap = &files["recap.html"];
+        assert!(recap.contains(&format!("<pre>{escaped}</pre>")), "{recap}");
+        assert!(message.contains("alice") && message.contains("carol"));
+    }
+
+    #[test]
+    fn driver_page_compares_with_the_all_time_best() {
+        let config = config("");
+        let prev = db(&[("alice", &[Some(58_000), None])]);
+        let current = db(&[("alice", &[Some(60_000), Some(62_000)])]);
+        let files = in_temp_dir("personal-bests", || {
+            report_files(&current, Some(&prev), &config, false)
+        });
+        let page = &files["alice.html"];
+        // Faster last time on the first stage, a PB on the second
+        assert!(
+            page.contains(r#"<td class="time">0:58.000 (+0:02.000)</td>"#),
+            "{page}"
+        );
+        assert_eq!(
+            page.matches(r#"<span class="pb">PB</span>"#).count(),
+            1,
+            "{page}"
+        );
+    }
+
+    #[test]
+    fn pages_are_byte_identical_across_runs() {
+        let config = config("");
+        let prev = db(&[("alice", &[Some(61_000), Some(62_000)])]);
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(62_000)]),
+            ("bob", &[Some(63_000), None]),
+        ]);
+        let (first, second) = in_temp_dir("identical", || {
+            let first = report_files(&current, Some(&prev), &config, false);
+            let second = report_files(&current, Some(&prev), &config, false);
+            (first, second)
+        });
+        assert!(first.contains_key("alice.html"));
+        assert!(first.contains_key("data.json"));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn unchanged_pages_keep_their_mtime() {
+        let config = config("");
+        let prev = db(&[("alice", &[Some(61_000), Some(62_000)])]);
+        let current = db(&[
+            ("alice", &[Some(60_000), Some(62_000)]),
+            ("bob", &[Some(63_000), None]),
+        ]);
+        let old = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mtimes = || {
+            std::fs::read_dir("public")
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let mtime = entry.metadata().unwrap().modified().unwrap();
+                    (entry.file_name().to_string_lossy().into_owned(), mtime)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let mtimes = in_temp_dir("mtime", || {
+            report_files(&current, Some(&prev), &config, false);
+            for entry in std::fs::read_dir("public").unwrap() {
+                let file = std::fs::File::options()
+                    .write(true)
+                    .open(entry.unwrap().path())
+                    .unwrap();
+                file.set_modified(old).unwrap();
+            }
+            report_files(&current, Some(&prev), &config, false);
+            mtimes()
+        });
+        assert!(mtimes.contains_key("alice.html"));
+        assert!(mtimes.contains_key("data.json"));
+        for (name, mtime) in &mtimes {
+            assert_eq!(*mtime, old, "{name} was rewritten");
+        }
+    }
+
+    #[test]
+    fn rally_notifications_only_reach_their_webhooks() {
+        let config = config(
+            "[rally_webhooks]\n\
+             kenya = [\"https://kenya.example.com\"]\n\
+             both = [\"https://kenya.example.com\", \"https://example.com/both\"]\n",
+        );
+        let prev = db(&[("alice", &[Some(61_000)])]);
+        let current = db(&[("alice", &[Some(60_000)])]);
+        let rows = diff(&current, Some(&prev), &config)["test"].clone();
+        let notifications: NotificationTable = ["kenya", "finland", "both"]
+            .into_iter()
+            .map(|rally| (rally.to_string(), rows.clone()))
+            .collect();
+
+        let routed = routed_notifications(&notifications, &config);
+        let rallys = |webhook: &str| routed[webhook].keys().cloned().collect_vec();
+        assert_eq!(routed.len(), 3);
+        assert_eq!(rallys("https://kenya.example.com"), ["kenya", "both"]);
+        assert_eq!(rallys("https://example.com/both"), ["both"]);
+        // Rallys without webhooks of their own go to the default one
+        assert_eq!(rallys("https://example.com"), ["finland"]);
+    }
+
+    #[test]
+    fn historical_times_show_their_original_date() {
+        let with_dates = config("show_set_dates = true");
+        let mut prev = db(&[("alice", &[Some(60_000), Some(70_000)])]);
+        // 2023-11-14
+        prev.results[0].stamp_observed_at(None, 1_700_000_000);
+        let mut current = db(&[("alice", &[Some(60_000), Some(69_000)])]);
+        // 2023-11-21
+        current.results[0].stamp_observed_at(Some(&prev.results[0]), 1_700_600_000);
+
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &with_dates,
+            TableMode::Absolute,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        assert_eq!(html.matches("set on 2023-11-14").count(), 1, "{html}");
+        assert_eq!(html.matches("set on 2023-11-21").count(), 1, "{html}");
+
+        let html = rally_table(
+            &current.rallys[0],
+            &current.results[0],
+            &config(""),
+            TableMode::Absolute,
+            &BTreeSet::new(),
+        )
+        .into_string();
+        assert!(!html.contains("set-on"), "{html}");
+    }
+
+    #[test]
+    fn rows_from_each_change() {
+        use Ordering::*;
+        let row = |time, local_rank, threshold| {
+            let prev = match time {
+                Less => 61_000,
+                Equal => 60_000,
+                Greater => 59_000,
+            };
+            let change = Change {
+                time,
+                local_rank,
+                world_rank: Equal,
+            };
+            Row::from_change(change, 2, "alice".to_string(), 60_000, prev, 2, threshold)
+        };
+        for (time, local_rank, marker) in [
+            (Less, Less, "^"),
+            (Less, Equal, "~"),
+            (Less, Greater, "v"),
+            (Equal, Less, " "),
+            (Equal, Equal, " "),
+            (Equal, Greater, "v"),
+            (Greater, Less, " "),
+            (Greater, Equal, " "),
+            (Greater, Greater, "v"),
+        ] {
+            assert_eq!(
+                row(time, local_rank, 0).marker(),
+                marker,
+                "time {time:?}, rank {local_rank:?}"
+            );
+        }
+        // Too small an improvement to mention, unless it changed the rank
+        assert!(row(Less, Equal, 2_000).is_unchanged());
+        assert_eq!(row(Less, Less, 2_000).marker(), "^");
+    }
+
+    #[test]
+    fn world_rank_column_only_with_world_ranks() {
+        let config = config("");
+        let mut current = db(&[("alice", &[Some(60_000)]), ("bob", &[Some(61_000)])]);
+        let without = in_temp_dir("no-world-ranks", || {
+            report_files(&current, None, &config, false)
+        });
+        current.results[0].driver_results[0].stages[0]
+            .as_mut()
+            .unwrap()
+            .world_rank = Some(1_234);
+        let with = in_temp_dir("world-ranks", || {
+            report_files(&current, None, &config, false)
+        });
+
+        for page in ["alice.html", "bob.html"] {
+            assert!(!without[page].contains("world rank"), "{page}");
+        }
+        assert!(with["alice.html"].contains("world rank"));
+        assert!(with["alice.html"].contains("1234"));
+        // Only alice has one
+        assert!(!with["bob.html"].contains("world rank"));
+        // alice's page and the stage's
+        let with_column = with
+            .iter()
+            .filter(|(_, contents)| contents.contains("world rank"))
+            .map(|(page, _)| page.as_str())
+            .collect_vec();
+        assert_eq!(with_column.len(), 2, "{with_column:?}");
+        assert!(with_column.contains(&"alice.html"));
+        assert!(without.values().all(|page| !page.contains("world rank")));
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{FullTime, PartialTime, StageWithLeaderboard};
+use serde::{Deserialize, Serialize};
+
+use crate::{FullTime, PartialTime, StageName, StageWithLeaderboard};
 
 pub fn stages(
     stages: &[StageWithLeaderboard],
@@ -11,7 +13,7 @@ pub fn stages(
     header.extend(
         stages
             .iter()
-            .map(|(stage, _group, weather)| format!("{stage} ({weather})")),
+            .map(|(stage, _group, weather)| format!("{} ({weather})", StageName(stage))),
     );
     let num_cols = header.len();
 
@@ -24,7 +26,7 @@ pub fn stages(
             String::new(),
             String::new(),
         ]];
-        let fastest_total = fastest_total.unwrap();
+        let fastest_total = fastest_total.expect("there are full times");
         driver.push([
             format_time(ft.total_time, true),
             format_delta(ft.total_time, fastest_total, true),
@@ -48,7 +50,7 @@ pub fn stages(
                             "{local_rank} | {}",
                             match world_rank {
                                 Some(r) => r.to_string(),
-                                None => "?".to_string(),
+                                None => "—".to_string(),
                             }
                         ),
                         format_percent(*t, fastest),
@@ -81,7 +83,10 @@ pub fn stages(
                         format_delta(*t, fastest, false),
                         art_of_rally_leaderboard_api::car_name(stages[i].1, pt.cars[i].unwrap())
                             .to_string(),
-                        format!("world: {}", rank.unwrap()),
+                        match rank {
+                            Some(r) => format!("world: {r}"),
+                            None => "world: —".to_string(),
+                        },
                         format_percent(*t, fastest),
                     ]
                 }
@@ -110,32 +115,277 @@ pub fn stages(
     (header, drivers)
 }
 
+/// How precisely times are shown. Times are always compared with full
+/// millisecond precision, this only affects formatting.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Precision {
+    Seconds,
+    Tenths,
+    Hundredths,
+    #[default]
+    Thousandths,
+}
+
+impl Precision {
+    fn decimals(self) -> u32 {
+        match self {
+            Precision::Seconds => 0,
+            Precision::Tenths => 1,
+            Precision::Hundredths => 2,
+            Precision::Thousandths => 3,
+        }
+    }
+
+    /// Milliseconds per unit of the last shown digit.
+    fn unit(self) -> usize {
+        10usize.pow(3 - self.decimals())
+    }
+
+    /// Round to the nearest shown unit, halves up.
+    fn round(self, ms: usize) -> usize {
+        let unit = self.unit();
+        ms.saturating_add(unit / 2) / unit * unit
+    }
+}
+
 pub fn format_time(ms: usize, long: bool) -> String {
+    format_time_with(ms, long, Precision::Thousandths)
+}
+
+/// `M:SS.mmm`, or `MM:SS.mmm` if `long`. Long times of an hour or more are
+/// `H:MM:SS.mmm`.
+pub fn format_time_with(ms: usize, long: bool, precision: Precision) -> String {
+    let ms = precision.round(ms);
     let minutes = ms / 1000 / 60;
     let seconds = (ms / 1000) % 60;
-    let millis = ms % 1000;
-    if long {
-        format!("{minutes:02}:{seconds:02}.{millis:03}")
+    let fraction = fraction(ms, precision);
+    if long && minutes >= 60 {
+        format!(
+            "{}:{:02}:{seconds:02}{fraction}",
+            minutes / 60,
+            minutes % 60
+        )
+    } else if long {
+        format!("{minutes:02}:{seconds:02}{fraction}")
+    } else {
+        format!("{minutes:01}:{seconds:02}{fraction}")
+    }
+}
+
+/// The decimals of an already rounded time, with the dot, or nothing for
+/// [`Precision::Seconds`].
+fn fraction(ms: usize, precision: Precision) -> String {
+    match precision.decimals() {
+        0 => String::new(),
+        decimals => format!(
+            ".{:0decimals$}",
+            (ms % 1000) / precision.unit(),
+            decimals = decimals as usize
+        ),
+    }
+}
+
+pub fn format_time_compact(ms: usize) -> String {
+    format_time_compact_with(ms, Precision::Thousandths)
+}
+
+/// Like a short [`format_time_with`], but without the minutes if there are
+/// none, so `0:43.210` is `43.210` and `0:00.500` is `0.500`. The widths
+/// vary, so it's not for columns that should line up.
+pub fn format_time_compact_with(ms: usize, precision: Precision) -> String {
+    let rounded = precision.round(ms);
+    if rounded < 60 * 1000 {
+        format!("{}{}", rounded / 1000, fraction(rounded, precision))
     } else {
-        format!("{minutes:01}:{seconds:02}.{millis:03}")
+        format_time_with(ms, false, precision)
     }
 }
 
 pub fn format_delta(ms: usize, compared: usize, long: bool) -> String {
-    if ms < compared {
-        format!("-{}", format_time(compared - ms, long))
-    } else if ms == compared {
+    format_delta_with(ms, compared, long, Precision::Thousandths)
+}
+
+/// The interval from a time to a faster one to compare with, like the fastest
+/// time on a stage. See [`format_signed_delta_with`] for how it's formatted.
+pub fn format_delta_with(ms: usize, compared: usize, long: bool, precision: Precision) -> String {
+    format_signed_delta_with(ms, compared, long, precision)
+}
+
+pub fn format_signed_delta(a: usize, b: usize, long: bool) -> String {
+    format_signed_delta_with(a, b, long, Precision::Thousandths)
+}
+
+/// How far `a` is behind `b`: `+M:SS.mmm` if `a` is slower, `-M:SS.mmm` if
+/// it's faster and blank padding if they're equal.
+pub fn format_signed_delta_with(a: usize, b: usize, long: bool, precision: Precision) -> String {
+    if a < b {
+        format!("-{}", format_time_with(b - a, long, precision))
+    } else if a == b {
         "         ".to_string()
     } else {
-        format!("+{}", format_time(ms - compared, long))
+        format!("+{}", format_time_with(a - b, long, precision))
     }
 }
 
 pub fn format_percent(ms: usize, fast: usize) -> String {
-    assert!(ms >= fast);
+    format_percent_with(ms, fast, Precision::Hundredths)
+}
+
+/// Like [`format_percent`], but with at most as many decimals as `precision`
+/// and never more than two.
+pub fn format_percent_with(ms: usize, fast: usize, precision: Precision) -> String {
+    let ms = at_least_fast(ms, fast);
+    if ms == fast {
+        "      ".to_string()
+    } else {
+        format!(
+            "{:.decimals$}%",
+            (ms as f32 * 100.0) / fast as f32,
+            decimals = precision.decimals().min(2) as usize
+        )
+    }
+}
+
+pub fn format_percent_gap(ms: usize, fast: usize) -> String {
+    format_percent_gap_with(ms, fast, Precision::Hundredths)
+}
+
+/// How much slower a time is than a faster one in percent, `+X.XX%`, and blank
+/// padding if they're equal. Decimals like [`format_percent_with`].
+pub fn format_percent_gap_with(ms: usize, fast: usize, precision: Precision) -> String {
+    let ms = at_least_fast(ms, fast);
     if ms == fast {
         "      ".to_string()
     } else {
-        format!("{:.2}%", (ms as f32 * 100.0) / fast as f32)
+        format!(
+            "+{:.decimals$}%",
+            ((ms - fast) as f32 * 100.0) / fast as f32,
+            decimals = precision.decimals().min(2) as usize
+        )
+    }
+}
+
+/// A time compared to a faster one should never be faster than it. If it is
+/// anyway, the times are mismatched somewhere, but that's not worth failing a
+/// whole report over, so warn and treat them as equal.
+fn at_least_fast(ms: usize, fast: usize) -> usize {
+    if ms < fast {
+        tracing::warn!("{ms} ms was compared to a faster time of {fast} ms");
+        fast
+    } else {
+        ms
+    }
+}
+
+/// Which of the buckets bounded by `bounds` a time is in, by its percentage of
+/// a faster time: 0 up to the first bound, 1 up to the second and so on.
+/// `bounds` should be in increasing order, like `[101.0, 105.0]`.
+pub fn pace_bucket(ms: usize, fast: usize, bounds: &[f32]) -> usize {
+    let percent = (ms as f32 * 100.0) / fast.max(1) as f32;
+    bounds.iter().filter(|&&bound| percent > bound).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_time_compact_drops_the_minutes() {
+        assert_eq!(format_time_compact(500), "0.500");
+        assert_eq!(format_time_compact(43_210), "43.210");
+        assert_eq!(format_time_compact(59_999), "59.999");
+        assert_eq!(format_time_compact(60_000), "1:00.000");
+        assert_eq!(format_time_compact(61_234), "1:01.234");
+    }
+
+    #[test]
+    fn format_time_compact_rounds_before_dropping_the_minutes() {
+        assert_eq!(
+            format_time_compact_with(59_999, Precision::Tenths),
+            "1:00.0"
+        );
+        assert_eq!(format_time_compact_with(59_949, Precision::Tenths), "59.9");
+        assert_eq!(format_time_compact_with(500, Precision::Seconds), "1");
+        assert_eq!(format_time_compact_with(499, Precision::Seconds), "0");
+    }
+
+    #[test]
+    fn format_time_boundaries() {
+        assert_eq!(format_time(0, false), "0:00.000");
+        assert_eq!(format_time(999, false), "0:00.999");
+        assert_eq!(format_time(1000, false), "0:01.000");
+        assert_eq!(format_time(59_999, false), "0:59.999");
+        assert_eq!(format_time(60_000, false), "1:00.000");
+        assert_eq!(format_time(3_599_999, false), "59:59.999");
+    }
+
+    #[test]
+    fn long_and_short_differ_only_in_minute_padding() {
+        for ms in [0, 999, 1000, 59_999, 60_000, 599_999, 3_599_999] {
+            let short = format_time(ms, false);
+            let long = format_time(ms, true);
+            assert_eq!(long.trim_start_matches('0'), short.trim_start_matches('0'));
+            assert_eq!(long.split_once(':').unwrap().0.len(), 2, "{long}");
+        }
+        assert_eq!(format_time(61_000, true), "01:01.000");
+        assert_eq!(format_time(600_000, true), "10:00.000");
+    }
+
+    #[test]
+    fn faster_times_saturate_instead_of_panicking() {
+        assert_eq!(at_least_fast(900, 1000), 1000);
+        assert_eq!(at_least_fast(1100, 1000), 1100);
+        assert_eq!(format_percent(900, 1000), format_percent(1000, 1000));
+        assert_eq!(
+            format_percent_gap(900, 1000),
+            format_percent_gap(1000, 1000)
+        );
+        assert_eq!(format_delta(1000, 1000, false).trim(), "");
+        assert_eq!(format_delta(900, 1000, false), "-0:00.100");
+    }
+
+    #[test]
+    fn each_precision_rounds_to_its_last_digit() {
+        use Precision::*;
+        for (precision, time, delta, percent) in [
+            (Seconds, "1:01", "+0:01", "102%"),
+            (Tenths, "1:01.2", "+0:01.2", "102.1%"),
+            (Hundredths, "1:01.23", "+0:01.23", "102.06%"),
+            (Thousandths, "1:01.234", "+0:01.234", "102.06%"),
+        ] {
+            assert_eq!(format_time_with(61_234, false, precision), time);
+            assert_eq!(format_delta_with(61_234, 60_000, false, precision), delta);
+            assert_eq!(format_percent_with(61_234, 60_000, precision), percent);
+        }
+    }
+
+    #[test]
+    fn rounding_carries_and_halves_go_up() {
+        use Precision::*;
+        assert_eq!(format_time_with(59_999, false, Seconds), "1:00");
+        assert_eq!(format_time_with(59_999, false, Tenths), "1:00.0");
+        assert_eq!(format_time_with(59_999, false, Hundredths), "1:00.00");
+        assert_eq!(format_time_with(59_999, false, Thousandths), "0:59.999");
+        assert_eq!(format_time_with(61_500, false, Seconds), "1:02");
+        assert_eq!(format_time_with(61_250, false, Tenths), "1:01.3");
+        assert_eq!(format_time_with(61_249, false, Tenths), "1:01.2");
+        assert_eq!(format_time_with(61_255, false, Hundredths), "1:01.26");
+    }
+
+    #[test]
+    fn long_times_of_an_hour_or_more_have_hours() {
+        assert_eq!(format_time(3_599_999, true), "59:59.999");
+        assert_eq!(format_time(3_600_000, true), "1:00:00.000");
+        assert_eq!(format_time(7_503_456, true), "2:05:03.456");
+        // Rounding up into the next hour
+        assert_eq!(
+            format_time_with(3_599_999, true, Precision::Tenths),
+            "1:00:00.0"
+        );
+        assert_eq!(format_delta(3_700_000, 100_000, true), "+1:00:00.000");
+        assert_eq!(format_delta(100_000, 3_700_000, true), "-1:00:00.000");
+        // Stage times stay in minutes
+        assert_eq!(format_time(3_600_000, false), "60:00.000");
     }
 }
@@ -0,0 +1,80 @@
+//! Fixtures shared by the library's tests and the binary's. It isn't behind
+//! `cfg(test)` since the binary's tests are built against the library as
+//! usual.
+
+use art_of_rally_leaderboard_api::{Area, Direction, Group, Stage, Weather};
+use itertools::Itertools as _;
+
+use crate::history::{DB_VERSION, Db};
+use crate::table_utils::Precision;
+use crate::{PlatformName, Rally, RallyResults, StageResult, StageWithLeaderboard};
+
+/// Kenya stage `stage_number`, forward in group B and dry weather.
+pub fn stage(stage_number: usize) -> StageWithLeaderboard {
+    (
+        Stage {
+            area: Area::Kenya,
+            stage_number: stage_number as _,
+            direction: Direction::Forward,
+        },
+        Group::GroupB,
+        Weather::Dry,
+    )
+}
+
+pub fn stage_result(time_ms: usize, local_rank: usize) -> StageResult {
+    StageResult {
+        car: 0,
+        time_ms,
+        local_rank,
+        world_rank: None,
+        world_entries: None,
+        observed_at: None,
+    }
+}
+
+/// Results on stages 1, 2, ... with each driver's time on each stage,
+/// ranked by time.
+pub fn rally_results(drivers: &[(&str, &[Option<usize>])]) -> RallyResults {
+    let stages = drivers.first().map_or(0, |(_, times)| times.len());
+    let driver_results = drivers
+        .iter()
+        .map(|(name, times)| {
+            let stage_results = times
+                .iter()
+                .enumerate()
+                .map(|(stage_idx, time)| {
+                    let time = (*time)?;
+                    let faster = drivers
+                        .iter()
+                        .filter(|(_, other)| other[stage_idx].is_some_and(|other| other < time))
+                        .count();
+                    Some(stage_result(time, faster + 1))
+                })
+                .collect();
+            (name.to_string(), stage_results)
+        })
+        .collect();
+    RallyResults::from_driver_results((1..=stages).map(stage).collect(), driver_results)
+}
+
+/// A snapshot with only the rally `title`.
+pub fn db(title: &str, results: RallyResults) -> Db {
+    let user_names = results
+        .driver_results
+        .iter()
+        .map(|driver| driver.name.clone())
+        .collect_vec();
+    Db {
+        version: DB_VERSION,
+        rallys: vec![Rally {
+            title: title.to_string(),
+            stages: results.stages.clone(),
+            precision: Precision::default(),
+        }],
+        results: vec![results],
+        platform: "steam".parse::<PlatformName>().unwrap().0,
+        user_ids: (0..user_names.len() as u64).collect(),
+        user_names,
+    }
+}